@@ -0,0 +1,71 @@
+use std::cmp::Reverse;
+use std::ffi::OsString;
+
+use ggez::{graphics::Rect, GameError, GameResult};
+
+use image::{GenericImage, RgbaImage};
+
+/// Maximum width and height of an atlas texture. Every OpenGL 3 implementation supports at least this size.
+pub const MAX_ATLAS_SIZE: u32 = 4096;
+
+/// empty pixels around each frame, so that linear filtering does not bleed neighbouring frames into each other
+const PADDING: u32 = 1;
+
+/// One texture of a packed atlas and the pixel rectangles of all frames that went into it.
+pub struct AtlasPage {
+    pub image: RgbaImage,
+    pub frames: Vec<(OsString, Rect)>,
+}
+
+/// Pack frames into as few pages as possible. Frames are placed on shelves sorted by height, which
+/// is close to optimal for our sprite sets where most frames in a set have the same size.
+pub fn pack(mut frames: Vec<(OsString, RgbaImage)>, max_size: u32) -> GameResult<Vec<AtlasPage>> {
+    frames.sort_by_key(|&(_, ref img)| Reverse(img.height()));
+
+    // first pass: find a place for each frame
+    let mut placements = Vec::with_capacity(frames.len());
+    let (mut page, mut x, mut y, mut shelf_height) = (0, 0, 0, 0);
+    for &(ref name, ref img) in &frames {
+        let (w, h) = (img.width() + PADDING, img.height() + PADDING);
+        if w > max_size || h > max_size {
+            return Err(GameError::ResourceLoadError(format!("{} does not fit into an atlas of size {}", name.to_string_lossy(), max_size)));
+        }
+
+        if x + w > max_size {
+            x = 0;
+            y += shelf_height;
+            shelf_height = 0;
+        }
+
+        if y + h > max_size {
+            page += 1;
+            x = 0;
+            y = 0;
+            shelf_height = 0;
+        }
+
+        placements.push((page, x, y));
+        x += w;
+        shelf_height = shelf_height.max(h);
+    }
+
+    // second pass: allocate pages just large enough and copy the frames
+    let n_pages = placements.last().map(|p| p.0 + 1).unwrap_or(0);
+    let mut sizes = vec![(0, 0); n_pages];
+    for (&(page, x, y), &(_, ref img)) in placements.iter().zip(&frames) {
+        sizes[page].0 = sizes[page].0.max(x + img.width());
+        sizes[page].1 = sizes[page].1.max(y + img.height());
+    }
+
+    let mut pages: Vec<_> = sizes.into_iter()
+        .map(|(w, h)| AtlasPage { image: RgbaImage::new(w, h), frames: Vec::new() })
+        .collect();
+
+    for ((page, x, y), (name, img)) in placements.into_iter().zip(frames) {
+        let rect = Rect::new(x as f32, y as f32, img.width() as f32, img.height() as f32);
+        pages[page].image.copy_from(&img, x, y);
+        pages[page].frames.push((name, rect));
+    }
+
+    Ok(pages)
+}
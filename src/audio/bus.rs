@@ -0,0 +1,167 @@
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::time::Duration;
+
+use rodio::Source;
+
+use config::{AudioConfig, BusConfig};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Bus {
+    Master,
+    Music,
+    Sfx,
+    Ui,
+}
+
+impl Bus {
+    pub const ALL: [Bus; 4] = [Bus::Master, Bus::Music, Bus::Sfx, Bus::Ui];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Bus::Master => "Master",
+            Bus::Music => "Music",
+            Bus::Sfx => "Effects",
+            Bus::Ui => "Interface",
+        }
+    }
+}
+
+/// Volume and mute state of all buses. This is shared with the audio thread, so changes are heard immediately.
+pub struct Buses {
+    // f32 volumes stored as bits
+    volumes: [AtomicUsize; 4],
+    muted: [AtomicBool; 4],
+}
+
+impl Default for Buses {
+    fn default() -> Self {
+        let one = || AtomicUsize::new(1.0f32.to_bits() as usize);
+        Buses {
+            volumes: [one(), one(), one(), one()],
+            muted: Default::default(),
+        }
+    }
+}
+
+impl Buses {
+    pub fn volume(&self, bus: Bus) -> f32 {
+        f32::from_bits(self.volumes[bus as usize].load(Ordering::Relaxed) as u32)
+    }
+
+    pub fn set_volume(&self, bus: Bus, volume: f32) {
+        let volume = volume.max(0.0).min(1.0);
+        self.volumes[bus as usize].store(volume.to_bits() as usize, Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self, bus: Bus) -> bool {
+        self.muted[bus as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, bus: Bus, muted: bool) {
+        self.muted[bus as usize].store(muted, Ordering::Relaxed);
+    }
+
+    /// effective gain of a bus, including the master bus
+    pub fn gain(&self, bus: Bus) -> f32 {
+        let own = |b| if self.is_muted(b) { 0.0 } else { self.volume(b) };
+        match bus {
+            Bus::Master => own(Bus::Master),
+            _ => own(Bus::Master) * own(bus),
+        }
+    }
+
+    pub fn apply_config(&self, config: &AudioConfig) {
+        for (&bus, c) in Bus::ALL.iter().zip(config.buses().iter()) {
+            self.set_volume(bus, c.volume);
+            self.set_muted(bus, c.muted);
+        }
+    }
+
+    pub fn to_config(&self, config: &mut AudioConfig) {
+        for (&bus, c) in Bus::ALL.iter().zip(config.buses_mut().iter_mut()) {
+            **c = BusConfig { volume: self.volume(bus), muted: self.is_muted(bus) };
+        }
+    }
+}
+
+/// Per-emitter gain, set by the game and read by the audio thread.
+pub struct EmitterGain {
+    culled: AtomicBool,
+    // f32 stored as bits
+    attenuation: AtomicUsize,
+}
+
+impl Default for EmitterGain {
+    fn default() -> Self {
+        EmitterGain {
+            culled: AtomicBool::new(false),
+            attenuation: AtomicUsize::new(1.0f32.to_bits() as usize),
+        }
+    }
+}
+
+impl EmitterGain {
+    pub fn is_culled(&self) -> bool {
+        self.culled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_culled(&self, culled: bool) {
+        self.culled.store(culled, Ordering::Relaxed);
+    }
+
+    pub fn attenuation(&self) -> f32 {
+        f32::from_bits(self.attenuation.load(Ordering::Relaxed) as u32)
+    }
+
+    pub fn set_attenuation(&self, gain: f32) {
+        self.attenuation.store(gain.to_bits() as usize, Ordering::Relaxed);
+    }
+
+    fn gain(&self) -> f32 {
+        if self.is_culled() {
+            0.0
+        } else {
+            self.attenuation()
+        }
+    }
+}
+
+/// Applies the gain of a bus, and optionally of an emitter, to a source. Culled sources keep playing, but silently.
+pub struct BusGain<S> {
+    source: S,
+    buses: Arc<Buses>,
+    bus: Bus,
+    emitter: Option<Arc<EmitterGain>>,
+    gain: f32,
+    countdown: usize,
+}
+
+impl<S> BusGain<S> {
+    /// the gain is refreshed every this many samples instead of reading the atomics for every sample
+    const REFRESH_INTERVAL: usize = 256;
+
+    pub fn new(source: S, buses: Arc<Buses>, bus: Bus, emitter: Option<Arc<EmitterGain>>) -> Self {
+        BusGain { source, buses, bus, emitter, gain: 0.0, countdown: 0 }
+    }
+}
+
+impl<S: Source<Item=f32>> Iterator for BusGain<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.countdown == 0 {
+            self.countdown = Self::REFRESH_INTERVAL;
+            self.gain = self.buses.gain(self.bus) * self.emitter.as_ref().map_or(1.0, |e| e.gain());
+        }
+        self.countdown -= 1;
+
+        self.source.next().map(|x| x * self.gain)
+    }
+}
+
+impl<S: Source<Item=f32>> Source for BusGain<S> {
+    fn current_frame_len(&self) -> Option<usize> { self.source.current_frame_len() }
+    fn channels(&self) -> u16 { self.source.channels() }
+    fn sample_rate(&self) -> u32 { self.source.sample_rate() }
+    fn total_duration(&self) -> Option<Duration> { self.source.total_duration() }
+}
@@ -0,0 +1,152 @@
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+use std::time::Duration;
+
+use rodio::Source;
+
+/// longest echo delay the reverb can produce, in seconds
+const MAX_DELAY: f32 = 0.2;
+
+/// relative lengths of the comb filters; mutually prime-ish so the echoes do not pile up
+const COMB_SPREAD: [f32; 4] = [1.0, 1.13, 1.27, 1.41];
+
+/// Acoustics of a level, as written in the level file. The actual reverb parameters are derived from
+/// these and the current tunnel radius and speed.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct EnvironmentDef {
+    /// level of the reverb relative to the dry sound, from 0 (dry) to 1
+    pub reverb: f32,
+    /// echo feedback in a tunnel of radius 1
+    pub decay: f32,
+    /// echo delay in seconds in a tunnel of radius 1
+    pub echo_delay: f32,
+    /// how much high frequencies are absorbed by the walls on every reflection, from 0 to 1
+    pub damping: f32,
+}
+
+impl Default for EnvironmentDef {
+    fn default() -> Self {
+        EnvironmentDef { reverb: 0.3, decay: 0.5, echo_delay: 0.05, damping: 0.4 }
+    }
+}
+
+impl EnvironmentDef {
+    /// Narrow tunnels echo faster and ring longer. At high speed the echoes fall behind, so there is less reverb.
+    pub fn params(&self, radius: f32, speed: f32) -> (f32, f32, f32, f32) {
+        let radius = radius.max(0.1);
+        let wet = self.reverb / (1.0 + 0.2 * speed.abs());
+        let feedback = (self.decay / radius.sqrt()).min(0.9);
+        let delay = (self.echo_delay * radius).min(MAX_DELAY / COMB_SPREAD[3]);
+        (wet, feedback, delay, self.damping)
+    }
+}
+
+/// Current reverb parameters, shared with the audio thread.
+pub struct Environment {
+    // f32 stored as bits
+    wet: AtomicUsize,
+    feedback: AtomicUsize,
+    delay: AtomicUsize,
+    damping: AtomicUsize,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        let env = Environment {
+            wet: AtomicUsize::new(0),
+            feedback: AtomicUsize::new(0),
+            delay: AtomicUsize::new(0),
+            damping: AtomicUsize::new(0),
+        };
+        let (wet, feedback, delay, damping) = EnvironmentDef::default().params(1.0, 0.0);
+        env.set(wet, feedback, delay, damping);
+        env
+    }
+}
+
+impl Environment {
+    pub fn set(&self, wet: f32, feedback: f32, delay: f32, damping: f32) {
+        self.wet.store(wet.to_bits() as usize, Ordering::Relaxed);
+        self.feedback.store(feedback.to_bits() as usize, Ordering::Relaxed);
+        self.delay.store(delay.to_bits() as usize, Ordering::Relaxed);
+        self.damping.store(damping.to_bits() as usize, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> (f32, f32, f32, f32) {
+        let load = |a: &AtomicUsize| f32::from_bits(a.load(Ordering::Relaxed) as u32);
+        (load(&self.wet), load(&self.feedback), load(&self.delay), load(&self.damping))
+    }
+}
+
+struct Comb {
+    buffer: Vec<f32>,
+    pos: usize,
+    filter: f32,
+}
+
+impl Comb {
+    fn process(&mut self, x: f32, delay: usize, feedback: f32, damping: f32) -> f32 {
+        let n = self.buffer.len();
+        let y = self.buffer[(self.pos + n - delay) % n];
+        self.filter = y * (1.0 - damping) + self.filter * damping;
+        self.buffer[self.pos] = x + self.filter * feedback;
+        self.pos = (self.pos + 1) % n;
+        y
+    }
+}
+
+/// Schroeder-style reverb: parallel damped comb filters. Only the reverberated signal comes out, so this is
+/// meant for a send that the dry sounds are mixed into, not for putting in front of each sound. Mono sources
+/// only.
+pub struct Reverb<S> {
+    source: S,
+    environment: Arc<Environment>,
+    combs: Vec<Comb>,
+    params: (f32, f32, f32, f32),
+    countdown: usize,
+}
+
+impl<S: Source<Item=f32>> Reverb<S> {
+    /// the parameters are refreshed every this many samples
+    const REFRESH_INTERVAL: usize = 256;
+
+    pub fn new(source: S, environment: Arc<Environment>) -> Self {
+        let len = (MAX_DELAY * source.sample_rate() as f32) as usize + 1;
+        let combs = COMB_SPREAD.iter()
+            .map(|_| Comb { buffer: vec![0.0; len], pos: 0, filter: 0.0 })
+            .collect();
+        Reverb { source, environment, combs, params: (0.0, 0.0, 0.0, 0.0), countdown: 0 }
+    }
+}
+
+impl<S: Source<Item=f32>> Iterator for Reverb<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.countdown == 0 {
+            self.countdown = Self::REFRESH_INTERVAL;
+            self.params = self.environment.get();
+        }
+        self.countdown -= 1;
+
+        let (wet, feedback, delay, damping) = self.params;
+
+        let x = self.source.next()?;
+
+        let rate = self.source.sample_rate() as f32;
+        let mut y = 0.0;
+        for (comb, spread) in self.combs.iter_mut().zip(COMB_SPREAD.iter()) {
+            let d = ((delay * spread * rate) as usize).max(1).min(comb.buffer.len() - 1);
+            y += comb.process(x, d, feedback, damping);
+        }
+
+        Some(y * wet / COMB_SPREAD.len() as f32)
+    }
+}
+
+impl<S: Source<Item=f32>> Source for Reverb<S> {
+    fn current_frame_len(&self) -> Option<usize> { None }
+    fn channels(&self) -> u16 { self.source.channels() }
+    fn sample_rate(&self) -> u32 { self.source.sample_rate() }
+    fn total_duration(&self) -> Option<Duration> { None }
+}
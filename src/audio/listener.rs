@@ -0,0 +1,72 @@
+use std::f32::consts::PI;
+
+use three_dee::{cylindric_vel_to_cartesian, Cartesian, Cylindric};
+
+/// How the volume of a sound decreases with distance. Distances are clamped to
+/// `[reference_distance, max_distance]`, and the gain is 1 at the reference distance.
+#[derive(Debug, Copy, Clone)]
+pub enum Rolloff {
+    /// no attenuation at all
+    None,
+    /// gain falls linearly to zero at the maximum distance
+    Linear,
+    /// physically motivated 1/d falloff, scaled by `factor`
+    Inverse { factor: f32 },
+    /// (d / reference)^-factor
+    Exponential { factor: f32 },
+}
+
+/// The ears of the player. Sound emitter positions are expressed relative to the listener, which follows
+/// the controlled ship around the ring and is oriented so that the tunnel axis is always above it.
+#[derive(Debug)]
+pub struct Listener {
+    pub pos: Cylindric,
+    pub vel: Cylindric,
+    pub rolloff: Rolloff,
+    pub reference_distance: f32,
+    pub max_distance: f32,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            pos: Cylindric::new(0.0, 0.0, 0.0),
+            vel: Cylindric::new(0.0, 0.0, 0.0),
+            rolloff: Rolloff::Inverse { factor: 1.0 },
+            reference_distance: 0.5,
+            max_distance: 50.0,
+        }
+    }
+}
+
+impl Listener {
+    /// position of `pos` in the listener's frame: x to the right, y up and z ahead
+    pub fn relative_position(&self, pos: Cylindric) -> Cartesian {
+        self.rotate(Cartesian::from(pos) - Cartesian::from(self.pos))
+    }
+
+    /// velocity of an emitter at `pos` relative to the listener, in the listener's frame
+    pub fn relative_velocity(&self, vel: Cylindric, pos: Cylindric) -> Cartesian {
+        self.rotate(cylindric_vel_to_cartesian(vel, pos) - cylindric_vel_to_cartesian(self.vel, self.pos))
+    }
+
+    /// gain of a sound at the given distance from the listener
+    pub fn attenuation(&self, distance: f32) -> f32 {
+        let reference = self.reference_distance;
+        let d = distance.max(reference).min(self.max_distance);
+        let gain = match self.rolloff {
+            Rolloff::None => 1.0,
+            Rolloff::Linear => 1.0 - (d - reference) / (self.max_distance - reference),
+            Rolloff::Inverse { factor } => reference / (reference + factor * (d - reference)),
+            Rolloff::Exponential { factor } => (d / reference).powf(-factor),
+        };
+        gain.max(0.0).min(1.0)
+    }
+
+    /// rotate around the tunnel axis so that the listener's "up" (towards the axis) becomes +y
+    fn rotate(&self, c: Cartesian) -> Cartesian {
+        let a = self.pos.w * 2.0 * PI;
+        let (sin, cos) = a.sin_cos();
+        Cartesian::new(c.x * cos + c.y * sin, -c.x * sin + c.y * cos, c.z)
+    }
+}
@@ -0,0 +1,478 @@
+pub mod bus;
+pub mod environment;
+pub mod listener;
+pub mod music;
+pub mod synth;
+
+use std::collections::VecDeque;
+use std::io::{Read, Seek};
+use std::mem;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::time::Duration;
+
+use ambisonic::{Ambisonic, AmbisonicBuilder, SoundController};
+
+use ggez::timer::duration_to_f64;
+
+use rodio::{Decoder, Source, decoder::DecoderError, dynamic_mixer::{DynamicMixerController, mixer}, source::Zero};
+
+use specs::prelude::*;
+
+use config::AudioConfig;
+use three_dee::Cartesian;
+
+pub use self::bus::{Bus, BusGain, Buses, EmitterGain};
+pub use self::environment::{Environment, EnvironmentDef, Reverb};
+pub use self::listener::{Listener, Rolloff};
+pub use self::music::{Music, MusicCue};
+use self::synth::SynthDef;
+
+const SAMPLE_RATE: u32 = 48000;
+
+/// Audio output shared by all game states. Cloning gives another handle to the same output.
+#[derive(Clone)]
+pub struct Audio {
+    pub ambisonic: Arc<Ambisonic>,
+    pub buses: Arc<Buses>,
+    /// reverb of the surroundings, applied to all positional sounds
+    pub environment: Arc<Environment>,
+    /// positional sounds also send their signal here, which goes through a single reverb shared by all of them
+    reverb_mixer: Arc<DynamicMixerController<f32>>,
+    reverb_controller: Arc<SoundController>,
+    /// sounds without a position, such as interface clicks, go here
+    pub ui_mixer: Arc<DynamicMixerController<f32>>,
+    ui_controller: Arc<SoundController>,
+    /// maximum number of sound emitters that are audible at the same time
+    pub max_voices: usize,
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        let ambisonic = AmbisonicBuilder::default().build();
+        let buses = Arc::new(Buses::default());
+
+        let (ui_mixer, source) = mixer(1, SAMPLE_RATE);
+        // keeps the mixer alive while there is nothing else to play
+        ui_mixer.add(Zero::new(1, SAMPLE_RATE));
+        let ui_controller = ambisonic.play(BusGain::new(source, buses.clone(), Bus::Ui, None));
+
+        // The sends are already scaled by their bus gains. The reverb is never positioned on purpose: the
+        // echoes of the tunnel come from all around, not from the direction of any one sound.
+        let environment = Arc::new(Environment::default());
+        let (reverb_mixer, source) = mixer(1, SAMPLE_RATE);
+        reverb_mixer.add(Zero::new(1, SAMPLE_RATE));
+        let reverb_controller = ambisonic.play(Reverb::new(source, environment.clone()));
+
+        Audio {
+            ambisonic: Arc::new(ambisonic),
+            buses,
+            environment,
+            reverb_mixer,
+            reverb_controller: Arc::new(reverb_controller),
+            ui_mixer,
+            ui_controller: Arc::new(ui_controller),
+            max_voices: 16,
+        }
+    }
+}
+
+impl Audio {
+    pub fn apply_config(&mut self, config: &AudioConfig) {
+        self.buses.apply_config(config);
+        self.max_voices = config.max_voices;
+    }
+
+    /// Play a sample once at a fixed position relative to the listener, without an entity to carry it.
+    pub fn play_at(&self, sample: &Sample, pos: Cartesian, volume: f32, pitch: f32) {
+        let (source, send) = split(sample.source(false, None).amplify(volume).speed(pitch));
+        let controller = self.ambisonic.play(BusGain::new(source, self.buses.clone(), Bus::Sfx, None));
+        self.reverb_mixer.add(BusGain::new(send, self.buses.clone(), Bus::Sfx, None));
+        // in Ambisonic z points up, but our z points into the screen
+        controller.adjust_position([pos.x, pos.z, pos.y]);
+    }
+
+    /// Play an interface sound.
+    pub fn play_ui(&self, sample: &Sample) {
+        self.ui_mixer.add(sample.source(false, None));
+    }
+}
+
+/// A decoded sound file, kept in memory so it can be played any number of times.
+#[derive(Clone)]
+pub struct Sample {
+    channels: u16,
+    sample_rate: u32,
+    data: Arc<Vec<f32>>,
+}
+
+impl Sample {
+    pub fn decode<R: Read + Seek + Send + 'static>(reader: R) -> Result<Self, DecoderError> {
+        let decoder = Decoder::new(reader)?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        Ok(Sample {
+            channels,
+            sample_rate,
+            data: Arc::new(decoder.convert_samples::<f32>().collect()),
+        })
+    }
+
+    pub fn duration(&self) -> Duration {
+        let frames = (self.data.len() / self.channels as usize) as u64;
+        Duration::from_nanos(frames * 1_000_000_000 / self.sample_rate as u64)
+    }
+
+    /// Source that plays the sample once or in a loop. It ends early when `stopped` is set.
+    pub fn source(&self, looping: bool, stopped: Option<Arc<AtomicBool>>) -> SampleSource {
+        SampleSource {
+            sample: self.clone(),
+            pos: 0,
+            looping,
+            stopped,
+        }
+    }
+}
+
+/// Plays a `Sample` once or in a loop.
+pub struct SampleSource {
+    sample: Sample,
+    pos: usize,
+    looping: bool,
+    stopped: Option<Arc<AtomicBool>>,
+}
+
+impl Iterator for SampleSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.stopped.as_ref().map_or(false, |s| s.load(Ordering::Relaxed)) {
+            return None
+        }
+
+        if self.pos >= self.sample.data.len() {
+            if !self.looping || self.sample.data.is_empty() {
+                return None
+            }
+            self.pos = 0;
+        }
+
+        self.pos += 1;
+        Some(self.sample.data[self.pos - 1])
+    }
+}
+
+impl Source for SampleSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.sample.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        if self.looping {
+            None
+        } else {
+            Some(self.sample.duration())
+        }
+    }
+}
+
+/// Handle to a looping sound.
+pub struct Voice {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Voice {
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Fades out the wrapped source once the shared fade length (in samples) is set to a non-zero value,
+/// and ends it when the fade is complete.
+struct FadeOut<S> {
+    source: S,
+    fade_len: Arc<AtomicUsize>,
+    gain: f32,
+    step: f32,
+}
+
+impl<S: Source<Item=f32>> Iterator for FadeOut<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.step == 0.0 {
+            let n = self.fade_len.load(Ordering::Relaxed);
+            if n > 0 {
+                self.step = 1.0 / n as f32;
+            }
+        } else {
+            self.gain -= self.step;
+            if self.gain <= 0.0 {
+                return None
+            }
+        }
+
+        self.source.next().map(|x| x * self.gain)
+    }
+}
+
+impl<S: Source<Item=f32>> Source for FadeOut<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+/// samples the tap hands over to its send at a time, so the send does not lock the buffer for every sample
+const SPLIT_CHUNK: usize = 256;
+
+struct SplitBuffer {
+    samples: VecDeque<f32>,
+    /// the tap has ended or was dropped; the send ends once it has played what is left
+    done: bool,
+}
+
+/// Plays a source in two places without computing it twice. The returned `Tap` plays the source, and the
+/// `TapSend` replays the samples the tap produced, a chunk later, so it must be pulled along with the tap.
+fn split<S: Source<Item=f32>>(source: S) -> (Tap<S>, TapSend) {
+    let buffer = Arc::new(Mutex::new(SplitBuffer { samples: VecDeque::new(), done: false }));
+    let send = TapSend {
+        buffer: buffer.clone(),
+        pending: VecDeque::new(),
+        channels: source.channels(),
+        sample_rate: source.sample_rate(),
+    };
+    let tap = Tap { source, buffer, chunk: Vec::with_capacity(SPLIT_CHUNK) };
+    (tap, send)
+}
+
+struct Tap<S> {
+    source: S,
+    buffer: Arc<Mutex<SplitBuffer>>,
+    chunk: Vec<f32>,
+}
+
+impl<S> Tap<S> {
+    fn flush(&mut self, done: bool) {
+        let mut buffer = self.buffer.lock().unwrap();
+        // drop what the send has not played yet if it falls behind by more than a quarter second
+        let excess = (buffer.samples.len() + self.chunk.len()).saturating_sub(SAMPLE_RATE as usize / 4);
+        for _ in 0..excess.min(buffer.samples.len()) {
+            buffer.samples.pop_front();
+        }
+        buffer.samples.extend(self.chunk.drain(..));
+        buffer.done |= done;
+    }
+}
+
+impl<S: Source<Item=f32>> Iterator for Tap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        match self.source.next() {
+            Some(x) => {
+                self.chunk.push(x);
+                if self.chunk.len() >= SPLIT_CHUNK {
+                    self.flush(false);
+                }
+                Some(x)
+            }
+            None => {
+                self.flush(true);
+                None
+            }
+        }
+    }
+}
+
+impl<S: Source<Item=f32>> Source for Tap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+impl<S> Drop for Tap<S> {
+    fn drop(&mut self) {
+        self.flush(true);
+    }
+}
+
+struct TapSend {
+    buffer: Arc<Mutex<SplitBuffer>>,
+    pending: VecDeque<f32>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl Iterator for TapSend {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pending.is_empty() {
+            let mut buffer = self.buffer.lock().unwrap();
+            mem::swap(&mut self.pending, &mut buffer.samples);
+            if self.pending.is_empty() {
+                // silence while waiting for the tap
+                return if buffer.done { None } else { Some(0.0) }
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+impl Source for TapSend {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Positional sound source attached to an entity. The sound ends when the component is dropped, which
+/// happens whenever the entity is deleted, either immediately or after an optional fade-out.
+#[derive(Component)]
+pub struct SoundEmitter {
+    pub mixer_controller: Arc<DynamicMixerController<f32>>,
+    pub spatial_controller: SoundController,
+    pub bus: Bus,
+    /// rough loudness of the emitter, used to decide which emitters to cull when there are too many
+    pub loudness: f32,
+    gain: Arc<EmitterGain>,
+    fade_out: Option<Duration>,
+    fade_len: Arc<AtomicUsize>,
+}
+
+impl SoundEmitter {
+    pub fn new(audio: &Audio, bus: Bus) -> Self {
+        let (mixer_controller, source) = mixer(1, SAMPLE_RATE);
+        // the mixed sounds of the emitter go to its position and to the shared reverb
+        let (source, send) = split(source);
+        let gain = Arc::new(EmitterGain::default());
+        let fade_len = Arc::new(AtomicUsize::new(0));
+        let spatial_controller = audio.ambisonic.play(FadeOut {
+            source: BusGain::new(source, audio.buses.clone(), bus, Some(gain.clone())),
+            fade_len: fade_len.clone(),
+            gain: 1.0,
+            step: 0.0,
+        });
+
+        audio.reverb_mixer.add(FadeOut {
+            source: BusGain::new(send, audio.buses.clone(), bus, Some(gain.clone())),
+            fade_len: fade_len.clone(),
+            gain: 1.0,
+            step: 0.0,
+        });
+
+        SoundEmitter {
+            mixer_controller,
+            spatial_controller,
+            bus,
+            loudness: 1.0,
+            gain,
+            fade_out: None,
+            fade_len,
+        }
+    }
+
+    pub fn with_loudness(mut self, loudness: f32) -> Self {
+        self.loudness = loudness;
+        self
+    }
+
+    pub fn is_culled(&self) -> bool {
+        self.gain.is_culled()
+    }
+
+    /// silence the emitter without stopping it
+    pub fn set_culled(&self, culled: bool) {
+        self.gain.set_culled(culled);
+    }
+
+    /// distance attenuation, as computed by the listener
+    pub fn attenuation(&self) -> f32 {
+        self.gain.attenuation()
+    }
+
+    pub fn set_attenuation(&self, gain: f32) {
+        self.gain.set_attenuation(gain);
+    }
+
+    /// keep playing for `duration` after the emitter is dropped, fading to silence
+    pub fn with_fade_out(mut self, duration: Duration) -> Self {
+        self.fade_out = Some(duration);
+        self
+    }
+
+    /// play `sample` once. `pitch` scales the playback speed.
+    pub fn play(&self, sample: &Sample, volume: f32, pitch: f32) {
+        self.mixer_controller.add(sample.source(false, None).amplify(volume).speed(pitch));
+    }
+
+    /// play a synthesized sound
+    pub fn play_synth(&self, def: &SynthDef) {
+        self.mixer_controller.add(def.build());
+    }
+
+    /// play `sample` in a loop until the returned `Voice` is stopped
+    pub fn play_looping(&self, sample: &Sample, volume: f32, pitch: f32) -> Voice {
+        let stopped = Arc::new(AtomicBool::new(false));
+        self.mixer_controller.add(sample.source(true, Some(stopped.clone())).amplify(volume).speed(pitch));
+        Voice { stopped }
+    }
+}
+
+impl Drop for SoundEmitter {
+    fn drop(&mut self) {
+        match self.fade_out {
+            Some(d) => {
+                let n = (duration_to_f64(d) * SAMPLE_RATE as f64) as usize;
+                self.fade_len.store(n.max(1), Ordering::Relaxed);
+            }
+            None => {
+                self.spatial_controller.stop();
+                // ends the send right away
+                self.fade_len.store(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
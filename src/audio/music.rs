@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::time::Duration;
+
+use ambisonic::SoundController;
+
+use ggez::{Context, GameError, GameResult};
+
+use rodio::{Decoder, Source, dynamic_mixer::{DynamicMixerController, mixer}, source::Zero};
+
+use toml;
+
+use super::{Audio, Bus, BusGain, SAMPLE_RATE};
+
+/// seconds it takes to crossfade between tracks, or to bring a stem in or out
+const FADE_TIME: f32 = 2.0;
+
+/// What a game state wants to hear: a music track and how intense the stems should be, from 0 to 1.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MusicCue {
+    pub track: &'static str,
+    pub intensity: f32,
+}
+
+impl MusicCue {
+    pub fn new(track: &'static str, intensity: f32) -> Self {
+        MusicCue { track, intensity }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MusicManifest {
+    #[serde(default)]
+    tracks: HashMap<String, Vec<StemDef>>,
+}
+
+/// One layer of a track. It is audible while the cue's intensity is at least `intensity`.
+#[derive(Debug, Deserialize)]
+struct StemDef {
+    file: String,
+    #[serde(default)]
+    intensity: f32,
+}
+
+/// The encoded file is kept in memory and decoded while playing.
+struct Stem {
+    data: SharedBytes,
+    intensity: f32,
+}
+
+#[derive(Clone)]
+struct SharedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Control over a playing stem, shared with the audio thread.
+struct StemControl {
+    // f32 stored as bits
+    target: AtomicUsize,
+    retired: AtomicBool,
+}
+
+impl StemControl {
+    fn set_target(&self, gain: f32) {
+        self.target.store(gain.to_bits() as usize, Ordering::Relaxed);
+    }
+
+    fn target(&self) -> f32 {
+        f32::from_bits(self.target.load(Ordering::Relaxed) as u32)
+    }
+}
+
+/// Loops a stem and glides its gain towards the target. Ends when retired and faded out.
+struct StemSource {
+    data: SharedBytes,
+    decoder: Decoder<Cursor<SharedBytes>>,
+    control: Arc<StemControl>,
+    gain: f32,
+    step: f32,
+}
+
+impl StemSource {
+    fn new(data: SharedBytes, control: Arc<StemControl>) -> Result<Self, GameError> {
+        let decoder = Decoder::new(Cursor::new(data.clone()))
+            .map_err(|e| GameError::ResourceLoadError(format!("{:?}", e)))?;
+        let step = 1.0 / (FADE_TIME * decoder.sample_rate() as f32 * decoder.channels() as f32);
+        Ok(StemSource { data, decoder, control, gain: 0.0, step })
+    }
+}
+
+impl Iterator for StemSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let target = self.control.target();
+        if self.gain < target {
+            self.gain = (self.gain + self.step).min(target);
+        } else if self.gain > target {
+            self.gain = (self.gain - self.step).max(target);
+        } else if self.gain == 0.0 && self.control.retired.load(Ordering::Relaxed) {
+            return None
+        }
+
+        let x = match self.decoder.next() {
+            Some(x) => x,
+            None => {
+                // restart the loop; the data was decoded successfully before, so this does not fail
+                self.decoder = Decoder::new(Cursor::new(self.data.clone())).ok()?;
+                self.decoder.next()?
+            }
+        };
+
+        Some(x as f32 / 32768.0 * self.gain)
+    }
+}
+
+impl Source for StemSource {
+    fn current_frame_len(&self) -> Option<usize> { None }
+    fn channels(&self) -> u16 { self.decoder.channels() }
+    fn sample_rate(&self) -> u32 { self.decoder.sample_rate() }
+    fn total_duration(&self) -> Option<Duration> { None }
+}
+
+struct PlayingTrack {
+    name: &'static str,
+    stems: Vec<(f32, Arc<StemControl>)>,
+}
+
+/// Plays layered music tracks on the music bus and crossfades when the requested track changes.
+pub struct Music {
+    mixer: Arc<DynamicMixerController<f32>>,
+    _controller: SoundController,
+    tracks: HashMap<String, Vec<Stem>>,
+    current: Option<PlayingTrack>,
+}
+
+impl Music {
+    pub fn new(audio: &Audio) -> Self {
+        let (mixer, source) = mixer(1, SAMPLE_RATE);
+        // keeps the mixer alive while there is nothing else to play
+        mixer.add(Zero::new(1, SAMPLE_RATE));
+        let controller = audio.ambisonic.play(BusGain::new(source, audio.buses.clone(), Bus::Music, None));
+        // music comes from straight ahead
+        controller.adjust_position([0.0, 1.0, 0.0]);
+
+        Music {
+            mixer,
+            _controller: controller,
+            tracks: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// load the track definitions from a TOML file
+    pub fn load(&mut self, ctx: &mut Context, path: &str) -> GameResult<()> {
+        let mut text = String::new();
+        ctx.filesystem.open(path)?.read_to_string(&mut text)?;
+
+        let manifest: MusicManifest = toml::from_str(&text)
+            .map_err(|e| GameError::ResourceLoadError(format!("{}: {}", path, e)))?;
+
+        for (name, defs) in manifest.tracks {
+            let mut stems = Vec::with_capacity(defs.len());
+            for def in defs {
+                let mut bytes = Vec::new();
+                ctx.filesystem.open(&def.file)?.read_to_end(&mut bytes)?;
+                let data = SharedBytes(Arc::new(bytes));
+                // decode once to fail early on broken files
+                Decoder::new(Cursor::new(data.clone()))
+                    .map_err(|e| GameError::ResourceLoadError(format!("track '{}' ({}): {:?}", name, def.file, e)))?;
+                stems.push(Stem { data, intensity: def.intensity });
+            }
+            self.tracks.insert(name, stems);
+        }
+
+        Ok(())
+    }
+
+    /// Follow the cue of the active game state. Called every frame; does nothing if the cue did not change.
+    pub fn update(&mut self, cue: Option<MusicCue>) {
+        let playing = self.current.as_ref().map(|t| t.name);
+        if playing != cue.map(|c| c.track) {
+            if let Some(track) = self.current.take() {
+                for (_, control) in track.stems {
+                    control.retired.store(true, Ordering::Relaxed);
+                    control.set_target(0.0);
+                }
+            }
+
+            let next = cue.map(|c| self.start(c.track));
+            self.current = next;
+        }
+
+        if let (Some(cue), Some(track)) = (cue, self.current.as_ref()) {
+            for &(intensity, ref control) in &track.stems {
+                control.set_target(if cue.intensity >= intensity { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    fn start(&self, name: &'static str) -> PlayingTrack {
+        let stems = match self.tracks.get(name) {
+            Some(stems) => &stems[..],
+            None => {
+                println!("unknown music track '{}'", name);
+                &[]
+            }
+        };
+
+        let mut playing = PlayingTrack { name, stems: Vec::with_capacity(stems.len()) };
+        for stem in stems {
+            let control = Arc::new(StemControl {
+                target: AtomicUsize::new(0.0f32.to_bits() as usize),
+                retired: AtomicBool::new(false),
+            });
+            if let Ok(source) = StemSource::new(stem.data.clone(), control.clone()) {
+                self.mixer.add(source);
+                playing.stems.push((stem.intensity, control));
+            }
+        }
+        playing
+    }
+}
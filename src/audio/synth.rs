@@ -0,0 +1,266 @@
+//! Building blocks for procedurally generated sounds. All sources are mono and produce `f32` samples,
+//! so they can be added directly to a `SoundEmitter`'s mixer.
+
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use rodio::Source;
+
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// lowest frequency an oscillator runs at; keeps sweeps from 0 Hz, which is easy to write in a data file,
+/// from dividing by zero
+const MIN_FREQUENCY: f32 = 1.0;
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    Noise,
+}
+
+/// Exponential frequency sweep from `from` to `to` Hz, taking `time` seconds.
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub struct Sweep {
+    pub from: f32,
+    pub to: f32,
+    pub time: f32,
+}
+
+impl Sweep {
+    pub fn constant(frequency: f32) -> Self {
+        Sweep { from: frequency, to: frequency, time: 0.0 }
+    }
+
+    fn clamped(self) -> Self {
+        Sweep { from: self.from.max(MIN_FREQUENCY), to: self.to.max(MIN_FREQUENCY), time: self.time }
+    }
+
+    fn frequency_at(&self, t: f32) -> f32 {
+        if t >= self.time {
+            self.to
+        } else {
+            self.from * (self.to / self.from).powf(t / self.time)
+        }
+    }
+}
+
+/// Infinite periodic waveform (or white noise) with a possibly sweeping frequency.
+pub struct Oscillator {
+    waveform: Waveform,
+    sweep: Sweep,
+    phase: f32,
+    n: u32,
+    rng: u32,
+}
+
+impl Oscillator {
+    pub fn new(waveform: Waveform, sweep: Sweep) -> Self {
+        Oscillator { waveform, sweep: sweep.clamped(), phase: 0.0, n: 0, rng: 0x2545_f491 }
+    }
+}
+
+impl Iterator for Oscillator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let t = self.n as f32 / SAMPLE_RATE as f32;
+        self.n = self.n.saturating_add(1);
+
+        self.phase += self.sweep.frequency_at(t) / SAMPLE_RATE as f32;
+        self.phase -= self.phase.floor();
+
+        let x = match self.waveform {
+            Waveform::Sine => (self.phase * 2.0 * PI).sin(),
+            Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Saw => 2.0 * self.phase - 1.0,
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => {
+                // xorshift is plenty random for audio
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 17;
+                self.rng ^= self.rng << 5;
+                self.rng as f32 / ::std::u32::MAX as f32 * 2.0 - 1.0
+            }
+        };
+        Some(x)
+    }
+}
+
+impl Source for Oscillator {
+    fn current_frame_len(&self) -> Option<usize> { None }
+    fn channels(&self) -> u16 { 1 }
+    fn sample_rate(&self) -> u32 { SAMPLE_RATE }
+    fn total_duration(&self) -> Option<Duration> { None }
+}
+
+/// Attack-decay-sustain-release envelope. Times are in seconds. The sustain level is held for `hold` seconds,
+/// or forever if `hold` is not set, in which case the sound lasts as long as its emitter.
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub struct Adsr {
+    #[serde(default)]
+    pub attack: f32,
+    #[serde(default)]
+    pub decay: f32,
+    #[serde(default = "one")]
+    pub sustain: f32,
+    #[serde(default)]
+    pub hold: Option<f32>,
+    #[serde(default)]
+    pub release: f32,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr { attack: 0.0, decay: 0.0, sustain: 1.0, hold: None, release: 0.0 }
+    }
+}
+
+impl Adsr {
+    /// envelope level at time `t`, or `None` when the envelope has ended
+    fn level(&self, t: f32) -> Option<f32> {
+        if t < self.attack {
+            return Some(t / self.attack)
+        }
+        let t = t - self.attack;
+
+        if t < self.decay {
+            return Some(1.0 - (1.0 - self.sustain) * t / self.decay)
+        }
+        let t = t - self.decay;
+
+        let hold = match self.hold {
+            None => return Some(self.sustain),
+            Some(hold) => hold,
+        };
+        if t < hold {
+            return Some(self.sustain)
+        }
+        let t = t - hold;
+
+        if t < self.release {
+            Some(self.sustain * (1.0 - t / self.release))
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Envelope<S> {
+    source: S,
+    adsr: Adsr,
+    n: u32,
+}
+
+impl<S: Source<Item=f32>> Iterator for Envelope<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let level = self.adsr.level(self.n as f32 / self.source.sample_rate() as f32)?;
+        self.n = self.n.saturating_add(1);
+        self.source.next().map(|x| x * level)
+    }
+}
+
+impl<S: Source<Item=f32>> Source for Envelope<S> {
+    fn current_frame_len(&self) -> Option<usize> { self.source.current_frame_len() }
+    fn channels(&self) -> u16 { self.source.channels() }
+    fn sample_rate(&self) -> u32 { self.source.sample_rate() }
+    fn total_duration(&self) -> Option<Duration> { None }
+}
+
+/// One-pole low-pass (or, with `high_pass` set, high-pass) filter.
+pub struct Filter<S> {
+    source: S,
+    alpha: f32,
+    state: f32,
+    high_pass: bool,
+}
+
+impl<S: Source<Item=f32>> Filter<S> {
+    fn new(source: S, cutoff: f32, high_pass: bool) -> Self {
+        let alpha = 1.0 - (-2.0 * PI * cutoff / source.sample_rate() as f32).exp();
+        Filter { source, alpha, state: 0.0, high_pass }
+    }
+}
+
+impl<S: Source<Item=f32>> Iterator for Filter<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let x = self.source.next()?;
+        self.state += self.alpha * (x - self.state);
+        if self.high_pass {
+            Some(x - self.state)
+        } else {
+            Some(self.state)
+        }
+    }
+}
+
+impl<S: Source<Item=f32>> Source for Filter<S> {
+    fn current_frame_len(&self) -> Option<usize> { self.source.current_frame_len() }
+    fn channels(&self) -> u16 { self.source.channels() }
+    fn sample_rate(&self) -> u32 { self.source.sample_rate() }
+    fn total_duration(&self) -> Option<Duration> { self.source.total_duration() }
+}
+
+/// Adds the synthesis building blocks to every mono `f32` source.
+pub trait SynthExt: Source<Item=f32> + Sized {
+    fn envelope(self, adsr: Adsr) -> Envelope<Self> {
+        Envelope { source: self, adsr, n: 0 }
+    }
+
+    /// `cutoff` in Hz
+    fn low_pass(self, cutoff: f32) -> Filter<Self> {
+        Filter::new(self, cutoff, false)
+    }
+
+    /// `cutoff` in Hz
+    fn high_pass(self, cutoff: f32) -> Filter<Self> {
+        Filter::new(self, cutoff, true)
+    }
+}
+
+impl<S: Source<Item=f32>> SynthExt for S {}
+
+/// Data description of a synthesized sound, as it appears in the `[synths]` section of the asset manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SynthDef {
+    pub waveform: Waveform,
+    #[serde(default)]
+    pub frequency: f32,
+    #[serde(default)]
+    pub sweep: Option<Sweep>,
+    #[serde(default)]
+    pub envelope: Adsr,
+    #[serde(default)]
+    pub low_pass: Option<f32>,
+    #[serde(default)]
+    pub high_pass: Option<f32>,
+    #[serde(default = "one")]
+    pub volume: f32,
+}
+
+impl SynthDef {
+    pub fn build(&self) -> Box<Source<Item=f32> + Send> {
+        let sweep = self.sweep.unwrap_or_else(|| Sweep::constant(self.frequency));
+        let mut source: Box<Source<Item=f32> + Send> = Box::new(Oscillator::new(self.waveform, sweep));
+
+        if let Some(cutoff) = self.low_pass {
+            source = Box::new(source.low_pass(cutoff));
+        }
+        if let Some(cutoff) = self.high_pass {
+            source = Box::new(source.high_pass(cutoff));
+        }
+
+        Box::new(source.envelope(self.envelope).amplify(self.volume))
+    }
+}
@@ -0,0 +1,190 @@
+use std::f32::consts::PI;
+
+use ggez::{graphics::{self, Point2}, Context};
+
+use rand::{self, Rng};
+
+use three_dee::{angle_difference, wrap_angle, Cartesian, Cylindric, Projection, ProjectionKind};
+use tunnel::TunnelPath;
+
+/// Where the world is seen from. Everything that is drawn goes through the camera, so shaking or rolling it
+/// moves the tunnel and the sprites together.
+#[derive(Debug)]
+pub struct Camera {
+    projection: Box<Projection>,
+    projection_kind: ProjectionKind,
+    /// position of the eye on the tunnel axis
+    pub z: f32,
+    /// how far the camera has flown through the tunnel; scrolls the tunnel walls
+    pub distance: f32,
+    /// rotation around the tunnel axis, in revolutions; the angle `roll` appears at the bottom of the screen
+    pub roll: f32,
+    /// how quickly the roll follows the player's ship, per second. 0 disables following.
+    pub roll_follow: f32,
+    /// horizontal field of view in radians
+    pub fov: f32,
+    /// current shake amplitude in screen units; decays over time
+    pub shake: f32,
+    /// the course of the tunnel, which bends everything that is drawn
+    pub path: TunnelPath,
+    shake_offset: Point2,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            projection: ProjectionKind::Hyperbolic.create(),
+            projection_kind: ProjectionKind::Hyperbolic,
+            z: 0.0,
+            distance: 0.0,
+            roll: 0.0,
+            roll_follow: 3.0,
+            fov: Camera::DEFAULT_FOV,
+            shake: 0.0,
+            path: TunnelPath::default(),
+            shake_offset: Point2::new(0.0, 0.0),
+        }
+    }
+}
+
+impl Camera {
+    /// the field of view at which the projection is used unscaled
+    pub const DEFAULT_FOV: f32 = PI / 2.0;
+
+    /// shake amplitude lost per second
+    const SHAKE_DECAY: f32 = 0.5;
+    const MAX_SHAKE: f32 = 0.2;
+
+    pub fn projection_kind(&self) -> ProjectionKind {
+        self.projection_kind
+    }
+
+    /// switch to another projection model
+    pub fn set_projection(&mut self, kind: ProjectionKind) {
+        self.projection = kind.create();
+        self.projection_kind = kind;
+    }
+
+    /// screen position of `pos`; the `z` of the result is the scale factor at that depth
+    pub fn project(&self, pos: Cylindric) -> Cartesian {
+        let p = self.projection.project(self.relative(self.bend(pos)));
+        let zoom = self.zoom();
+        Cartesian::new(p.x * zoom + self.shake_offset.x, p.y * zoom + self.shake_offset.y, p.z * zoom)
+    }
+
+    /// the point at depth `z` under a position on the screen, e.g. for placing things with the mouse
+    pub fn unproject_at_depth(&self, screen: Point2, z: f32) -> Option<Cylindric> {
+        self.projection.unproject_at_depth(self.unshake(screen), z - self.z)
+            .map(|p| self.unbend(self.absolute(p)))
+    }
+
+    /// The point on the cylinder of radius `r` under a position on the screen, e.g. for aiming at ships
+    /// flying at that radius. Where the tunnel bends this is only approximate, because the depth is found
+    /// before the bend is undone.
+    pub fn unproject_at_radius(&self, screen: Point2, r: f32) -> Option<Cylindric> {
+        self.projection.unproject_at_radius(self.unshake(screen), r)
+            .filter(|p| p.z >= 0.0)
+            .map(|p| self.unbend(self.absolute(p)))
+    }
+
+    /// convert a window position in pixels to screen coordinates, as set up by the game states
+    pub fn window_to_screen(ctx: &Context, x: i32, y: i32) -> Point2 {
+        let (w, h) = graphics::get_size(ctx);
+        let area = graphics::get_screen_coordinates(ctx);
+        Point2::new(area.x + x as f32 / w as f32 * area.w, area.y + y as f32 / h as f32 * area.h)
+    }
+
+    /// scale factor of things at depth `z`
+    pub fn scale(&self, z: f32) -> f32 {
+        self.projection.factor(z - self.z) * self.zoom()
+    }
+
+    /// on-screen distance from the tunnel axis of a point at radius `r` and depth `z`
+    pub fn radius(&self, r: f32, z: f32) -> f32 {
+        self.projection.radius(r, z - self.z) * self.zoom()
+    }
+
+    /// factor by which the tunnel narrows or widens at depth `z`
+    pub fn radius_scale(&self, z: f32) -> f32 {
+        self.path.radius_scale(self.distance + z - self.z)
+    }
+
+    /// on-screen rotation of something at `pos` that is upright relative to the tunnel wall
+    pub fn rotation(&self, pos: Cylindric) -> f32 {
+        let normal = self.path.wall_normal(self.distance + pos.z - self.z, pos.w);
+        -(normal - self.roll) * 2.0 * PI
+    }
+
+    /// Displace `pos` along with the tunnel: `w` is mapped onto the shape of the cross-section, which is then
+    /// scaled and moved with the centreline. The camera always looks down the centreline where it is, so
+    /// only the difference to the centreline at the camera is visible.
+    fn bend(&self, pos: Cylindric) -> Cylindric {
+        let s = self.distance + pos.z - self.z;
+        let (x, y) = self.offset(pos.z);
+        let (px, py) = self.path.wall_point(s, pos.w);
+        let r = pos.r * self.radius_scale(pos.z);
+        Cylindric::from(Cartesian::new(px * r + x, py * r + y, pos.z))
+    }
+
+    /// inverse of `bend`
+    fn unbend(&self, pos: Cylindric) -> Cylindric {
+        let s = self.distance + pos.z - self.z;
+        let (x, y) = self.offset(pos.z);
+        let straight = Cylindric::from(Cartesian::from(pos) - Cartesian::new(x, y, 0.0));
+        let w = self.path.wall_angle(s, straight.w);
+        let (px, py) = self.path.wall_point(s, w);
+        let wall = (px * px + py * py).sqrt();
+        Cylindric::new(straight.r / (wall * self.radius_scale(pos.z)), w, straight.z)
+    }
+
+    /// offset of the centreline at depth `z` relative to the centreline at the camera
+    fn offset(&self, z: f32) -> (f32, f32) {
+        let (x0, y0) = self.path.offset(self.distance);
+        let (x, y) = self.path.offset(self.distance + z - self.z);
+        (x - x0, y - y0)
+    }
+
+    /// `pos` relative to the camera
+    pub fn relative(&self, pos: Cylindric) -> Cylindric {
+        Cylindric::new(pos.r, pos.w - self.roll, pos.z - self.z)
+    }
+
+    /// inverse of `relative`
+    fn absolute(&self, pos: Cylindric) -> Cylindric {
+        Cylindric::new(pos.r, wrap_angle(pos.w + self.roll), pos.z + self.z)
+    }
+
+    /// undo the shake and zoom that `project` applies
+    fn unshake(&self, screen: Point2) -> Point2 {
+        let zoom = self.zoom();
+        Point2::new((screen.x - self.shake_offset.x) / zoom, (screen.y - self.shake_offset.y) / zoom)
+    }
+
+    fn zoom(&self) -> f32 {
+        1.0 / (self.fov * 0.5).tan()
+    }
+
+    pub fn add_shake(&mut self, amount: f32) {
+        self.shake = (self.shake + amount).min(Camera::MAX_SHAKE);
+    }
+
+    /// Advance the camera by `dt` seconds: fly on at `speed`, roll towards the angle `target`
+    /// (if there is one) and let the shake die down.
+    pub fn update(&mut self, dt: f32, speed: f32, target: Option<f32>) {
+        self.distance += speed * dt;
+
+        if let Some(target) = target {
+            // turn the shorter way around
+            let d = angle_difference(self.roll, target);
+            self.roll = wrap_angle(self.roll + d * (self.roll_follow * dt).min(1.0));
+        }
+
+        self.shake = (self.shake - Camera::SHAKE_DECAY * dt).max(0.0);
+        self.shake_offset = if self.shake > 0.0 {
+            let a = rand::thread_rng().gen_range(0.0, 2.0 * PI);
+            Point2::new(a.cos() * self.shake, a.sin() * self.shake)
+        } else {
+            Point2::new(0.0, 0.0)
+        };
+    }
+}
@@ -4,17 +4,30 @@ use ambisonic::AmbisonicBuilder;
 
 use specs::prelude::*;
 
-pub use audio::{Audio, SoundEmitter};
+pub use audio::{Audio, EnvironmentDef, Listener, SoundEmitter};
+pub use camera::Camera;
 use inputstate::InputState;
-use resources::Resources;
-use three_dee::Cylindric;
+use particles::{ParticleEffect, Particles};
+use resources::{AnimationId, ImageId, Resources};
+use three_dee::{angle_difference, wrap_angle, Cylindric};
+use tunnel::TunnelPath;
+use weapons::{WeaponId, Weapons};
 
-pub fn register_components(world: &mut World) {
+pub fn register_components(world: &mut World, audio: Audio) {
     world.register::<Acc>();
+    world.register::<Animated>();
+    world.register::<Boss>();
     world.register::<Controlled>();
+    world.register::<Enemy>();
+    world.register::<Energy>();
+    world.register::<Health>();
+    world.register::<Lane>();
+    world.register::<Launcher>();
+    world.register::<Obstacle>();
+    world.register::<ParticleEmitter>();
     world.register::<Pos>();
-    world.register::<RocketLauncher>();
-    world.register::<RocketProjectile>();
+    world.register::<Projectile>();
+    world.register::<ShipHandling>();
     world.register::<SoundEmitter>();
     world.register::<Sprite>();
     world.register::<Vel>();
@@ -22,16 +35,140 @@ pub fn register_components(world: &mut World) {
     world.add_resource(DeltaTime(Duration::from_secs(0)));
     world.add_resource(InputState::new());
     world.add_resource(Resources::new());
-    world.add_resource(Audio::default());
+    world.add_resource(audio);
+    world.add_resource(Listener::default());
+    world.add_resource(Tunnel::default());
+    world.add_resource(GravityField::default());
+    world.add_resource(Camera::default());
+    world.add_resource(Particles::default());
+    world.add_resource(EnvironmentDef::default());
+    world.add_resource(Weapons::default());
+    world.add_resource(TargetLock::default());
 }
 
 #[derive(Default)]
 pub struct DeltaTime(pub Duration);
 
+/// The enemy that the player's homing weapons are locked onto.
+#[derive(Default)]
+pub struct TargetLock(pub Option<Entity>);
+
+/// Shape of the tunnel and how fast the player is flying through it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Tunnel {
+    pub radius: f32,
+    /// distance travelled along the tunnel axis per second when cruising
+    pub speed: f32,
+    /// speed while the player boosts
+    pub boost_speed: f32,
+    /// speed while the player brakes
+    pub brake_speed: f32,
+    /// how quickly the speed changes, per second
+    pub acceleration: f32,
+    /// the speed the player is actually flying at; everything in the world is placed relative to `speed`, so
+    /// the difference moves it past the ship
+    #[serde(skip)]
+    pub current_speed: f32,
+    /// number of flight lanes, from the wall towards the axis
+    pub lanes: u32,
+    /// distance between lanes, as a fraction of the radius
+    pub lane_spacing: f32,
+    pub path: TunnelPath,
+}
+
+impl Default for Tunnel {
+    fn default() -> Self {
+        Tunnel {
+            radius: 1.0,
+            speed: 1.0,
+            boost_speed: 2.0,
+            brake_speed: 0.5,
+            acceleration: 2.0,
+            current_speed: 1.0,
+            lanes: 1,
+            lane_spacing: 0.35,
+            path: TunnelPath::default(),
+        }
+    }
+}
+
+impl Tunnel {
+    /// radius of a lane; lane 0 runs along the tunnel wall
+    pub fn lane_radius(&self, lane: u32) -> f32 {
+        let lane = lane.min(self.lanes.max(1) - 1);
+        self.radius * (1.0 - lane as f32 * self.lane_spacing).max(0.0)
+    }
+
+    /// how much faster or slower than cruising speed the player is flying
+    pub fn relative_speed(&self) -> f32 {
+        self.current_speed - self.speed
+    }
+
+    /// playback speed for sounds, so that the world sounds faster when the player is
+    pub fn pitch(&self) -> f32 {
+        if self.speed > 0.0 {
+            (self.current_speed / self.speed).sqrt().max(0.5).min(2.0)
+        } else {
+            1.0
+        }
+    }
+}
+
 #[derive(Debug, Component, Default)]
 #[storage(NullStorage)]
 pub struct Controlled;
 
+#[derive(Debug, Component, Default)]
+#[storage(NullStorage)]
+pub struct Enemy;
+
+/// An enemy that gets its own music while it is ahead of the camera.
+#[derive(Debug, Component, Default)]
+#[storage(NullStorage)]
+pub struct Boss;
+
+#[derive(Debug, Component)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Health { current: max, max }
+    }
+
+    pub fn fraction(&self) -> f32 {
+        (self.current / self.max).max(0.0)
+    }
+}
+
+/// Energy that the ship spends on boosting and that recharges slowly otherwise.
+#[derive(Debug, Component)]
+pub struct Energy {
+    pub current: f32,
+    pub max: f32,
+    /// Set when boosting used up all energy. Boosting only resumes once the boost key has been released,
+    /// so that the ship does not stutter between boosting and recharging.
+    pub depleted: bool,
+}
+
+impl Energy {
+    /// energy used per second of boosting
+    pub const DRAIN: f32 = 25.0;
+    /// energy regained per second while not boosting
+    pub const RECHARGE: f32 = 10.0;
+
+    pub fn new(max: f32) -> Self {
+        Energy { current: max, max, depleted: false }
+    }
+
+    pub fn fraction(&self) -> f32 {
+        (self.current / self.max).max(0.0)
+    }
+}
+
 #[derive(Debug, Copy, Clone, Component)]
 #[storage(VecStorage)]
 pub struct Pos(pub Cylindric);
@@ -70,28 +207,249 @@ pub enum SpriteSize {
 
 #[derive(Debug, Component)]
 #[storage(VecStorage)]
-pub struct Sprite(pub usize, pub SpriteSize);
+pub struct Sprite(pub ImageId, pub SpriteSize);
 
 impl Sprite {
-    pub fn new_auto(id: usize, scale: f32) -> Self {
+    pub fn new_auto(id: ImageId, scale: f32) -> Self {
         Sprite(id, SpriteSize::Auto{scale})
     }
 
-    pub fn new_fixed(id: usize, width: f32, height: f32) -> Self {
+    pub fn new_fixed(id: ImageId, width: f32, height: f32) -> Self {
         Sprite(id, SpriteSize::Fixed{width, height})
     }
 }
 
+/// Cycles the image of the entity's `Sprite` through the frames of an animation.
 #[derive(Debug, Component)]
-pub enum RocketLauncher {
+#[storage(VecStorage)]
+pub struct Animated {
+    pub animation: AnimationId,
+    pub time: Duration,
+}
+
+impl Animated {
+    pub fn new(animation: AnimationId) -> Self {
+        Animated { animation, time: Duration::from_secs(0) }
+    }
+}
+
+/// The black hole at the end of the tunnel. Its effects grow with depth and diverge at the horizon.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct GravityField {
+    /// depth of the event horizon
+    pub horizon: f32,
+    /// angular drift in revolutions per second, halfway to the horizon
+    pub frame_dragging: f32,
+    /// acceleration towards the tunnel axis, halfway to the horizon
+    pub pull: f32,
+    /// how much time slows down for things near the horizon, from 0 (not at all) to 1
+    pub time_dilation: f32,
+}
+
+impl Default for GravityField {
+    fn default() -> Self {
+        GravityField { horizon: 40.0, frame_dragging: 0.0, pull: 0.0, time_dilation: 0.0 }
+    }
+}
+
+impl GravityField {
+    /// 0 at the camera, 1 halfway to the horizon and infinite at the horizon
+    fn strength(&self, z: f32) -> f32 {
+        let f = (z / self.horizon).max(0.0).min(0.99);
+        f / (1.0 - f)
+    }
+
+    /// angular velocity with which space itself rotates at depth `z`
+    pub fn drag_velocity(&self, z: f32) -> f32 {
+        self.frame_dragging * self.strength(z)
+    }
+
+    /// acceleration towards the axis at depth `z`
+    pub fn radial_acceleration(&self, z: f32) -> f32 {
+        -self.pull * self.strength(z)
+    }
+
+    /// time scale of an observer at depth `z`
+    pub fn dilation(&self, z: f32) -> f32 {
+        let f = (z / self.horizon).max(0.0).min(1.0);
+        (1.0 - self.time_dilation * f).max(0.01).sqrt()
+    }
+}
+
+/// How a ship moves around the ring. Angles are in revolutions, so `max_speed` is in revolutions per second.
+#[derive(Debug, Copy, Clone, Component, Deserialize)]
+#[serde(default)]
+pub struct ShipHandling {
+    pub mass: f32,
+    /// steering force of the engines
+    pub thrust: f32,
+    /// velocity lost per second, relative to the velocity and divided by the mass
+    pub drag: f32,
+    pub max_speed: f32,
+    /// top speed when changing lanes, in units per second
+    pub radial_speed: f32,
+}
+
+impl Default for ShipHandling {
+    fn default() -> Self {
+        ShipHandling { mass: 1.0, thrust: 4.0, drag: 1.0, max_speed: 0.6, radial_speed: 1.5 }
+    }
+}
+
+impl ShipHandling {
+    /// New angular velocity of a ship at angle `w` moving at `vel` that is steered towards `target`, or just
+    /// slows down if there is no target. The ship brakes in time to stop at the target.
+    pub fn steer(&self, w: f32, vel: f32, target: Option<f32>, dt: f32) -> f32 {
+        let distance = target.map(|target| angle_difference(w, target));
+        self.approach(distance, vel, self.max_speed, dt)
+    }
+
+    /// new radial velocity of a ship at radius `r` moving at `vel` towards the radius `target`
+    pub fn steer_radial(&self, r: f32, vel: f32, target: f32, dt: f32) -> f32 {
+        self.approach(Some(target - r), vel, self.radial_speed, dt)
+    }
+
+    fn approach(&self, distance: Option<f32>, vel: f32, max_speed: f32, dt: f32) -> f32 {
+        let accel = self.thrust / self.mass;
+
+        let desired = match distance {
+            // the fastest speed from which we can still stop at the target
+            Some(d) => d.signum() * (2.0 * accel * d.abs()).sqrt().min(max_speed),
+            None => 0.0,
+        };
+
+        let vel = vel + (desired - vel).max(-accel * dt).min(accel * dt);
+        vel - vel * (self.drag / self.mass * dt).min(1.0)
+    }
+}
+
+/// The flight lane a ship is moving to; see `Tunnel::lane_radius`.
+#[derive(Debug, Copy, Clone, Component, Default)]
+pub struct Lane(pub u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObstacleKind {
+    /// solid wall covering the range
+    Wall,
+    /// solid wall with a gap where the range is
+    Gate,
+    /// energy field covering the range; it hurts less than a wall
+    Barrier,
+}
+
+/// Level geometry that spans part of the ring at a fixed place in the tunnel. Angles are in revolutions.
+#[derive(Debug, Copy, Clone, Component, Deserialize)]
+pub struct Obstacle {
+    pub kind: ObstacleKind,
+    /// distance along the tunnel
+    pub at: f32,
+    pub from: f32,
+    pub to: f32,
+    /// how far the obstacle reaches from the wall towards the axis, as a fraction of the radius
+    #[serde(default = "Obstacle::default_height")]
+    pub height: f32,
+    /// set once the player has flown past
+    #[serde(skip)]
+    pub passed: bool,
+}
+
+impl Obstacle {
+    fn default_height() -> f32 {
+        1.0
+    }
+
+    /// the angular range that is solid, as start and length
+    pub fn solid_span(&self) -> (f32, f32) {
+        let gap = wrap_angle(self.to - self.from);
+        match self.kind {
+            ObstacleKind::Wall | ObstacleKind::Barrier => (wrap_angle(self.from), gap),
+            ObstacleKind::Gate => (wrap_angle(self.to), 1.0 - gap),
+        }
+    }
+
+    /// whether something at angle `w` and radius `r` hits the obstacle in a tunnel with the given radius
+    pub fn blocks(&self, w: f32, r: f32, radius: f32) -> bool {
+        let (start, length) = self.solid_span();
+        r >= radius * (1.0 - self.height) && wrap_angle(w - start) <= length
+    }
+
+    /// health lost when the player's ship hits the obstacle
+    pub fn damage(&self) -> f32 {
+        match self.kind {
+            ObstacleKind::Wall | ObstacleKind::Gate => 30.0,
+            ObstacleKind::Barrier => 10.0,
+        }
+    }
+}
+
+/// Continuously spawns particles at the entity's position while active.
+#[derive(Debug, Component)]
+pub struct ParticleEmitter {
+    pub effect: ParticleEffect,
+    /// particles per second
+    pub rate: f32,
+    pub active: bool,
+    /// fractional particles carried over to the next update
+    pub pending: f32,
+}
+
+impl ParticleEmitter {
+    pub fn new(effect: ParticleEffect, rate: f32) -> Self {
+        ParticleEmitter { effect, rate, active: true, pending: 0.0 }
+    }
+
+    pub fn inactive(mut self) -> Self {
+        self.active = false;
+        self
+    }
+}
+
+/// The weapons of a ship. Only the selected one is fired.
+#[derive(Debug, Component)]
+pub struct Launcher {
+    pub weapons: Vec<WeaponId>,
+    pub selected: usize,
+    pub state: LauncherState,
+}
+
+impl Launcher {
+    pub fn new(weapons: Vec<WeaponId>) -> Self {
+        Launcher { weapons, selected: 0, state: LauncherState::Ready }
+    }
+
+    pub fn weapon(&self) -> Option<WeaponId> {
+        self.weapons.get(self.selected).cloned()
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.weapons.is_empty() {
+            self.selected = (self.selected + 1) % self.weapons.len();
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum LauncherState {
     Ready,
     Fire,
     Recharge(Duration),
 }
 
+/// Something fired from a weapon, in one of the phases of its flight.
 #[derive(Debug, Component)]
-pub enum RocketProjectile {
-    Launching(f32, Duration),
+pub struct Projectile {
+    pub weapon: WeaponId,
+    pub phase: ProjectilePhase,
+    /// the enemy a homing projectile steers towards
+    pub target: Option<Entity>,
+}
+
+/// The phases of a projectile's flight, each with the time that is left of it.
+#[derive(Debug, Copy, Clone)]
+pub enum ProjectilePhase {
+    Launching(Duration),
     Accelerating(Duration),
     Flying(Duration),
 }
\ No newline at end of file
@@ -0,0 +1,88 @@
+use std::io::{Read, Write};
+
+use ggez::{Context, GameError, GameResult};
+
+use toml;
+
+/// User settings, stored in the user's config directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub audio: AudioConfig,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct BusConfig {
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        BusConfig { volume: 1.0, muted: false }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub master: BusConfig,
+    pub music: BusConfig,
+    pub sfx: BusConfig,
+    pub ui: BusConfig,
+    /// maximum number of sound emitters that are audible at the same time
+    pub max_voices: usize,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            master: BusConfig::default(),
+            music: BusConfig::default(),
+            sfx: BusConfig::default(),
+            ui: BusConfig::default(),
+            max_voices: 16,
+        }
+    }
+}
+
+impl AudioConfig {
+    /// bus settings in the order of `Bus::ALL`
+    pub fn buses(&self) -> [&BusConfig; 4] {
+        [&self.master, &self.music, &self.sfx, &self.ui]
+    }
+
+    pub fn buses_mut(&mut self) -> [&mut BusConfig; 4] {
+        [&mut self.master, &mut self.music, &mut self.sfx, &mut self.ui]
+    }
+}
+
+impl Config {
+    const PATH: &'static str = "/config.toml";
+
+    /// Load the config file. Falls back to the defaults if there is none or it cannot be read.
+    pub fn load(ctx: &mut Context) -> Config {
+        if !ctx.filesystem.is_file(Config::PATH) {
+            return Config::default()
+        }
+
+        let result = ctx.filesystem.open(Config::PATH)
+            .and_then(|mut file| {
+                let mut text = String::new();
+                file.read_to_string(&mut text)?;
+                Ok(text)
+            })
+            .and_then(|text| toml::from_str(&text).map_err(|e| GameError::ConfigError(e.to_string())));
+
+        result.unwrap_or_else(|e| {
+            println!("could not load {}, using default settings: {}", Config::PATH, e);
+            Config::default()
+        })
+    }
+
+    pub fn save(&self, ctx: &mut Context) -> GameResult<()> {
+        let text = toml::to_string(self).map_err(|e| GameError::ConfigError(e.to_string()))?;
+        ctx.filesystem.create(Config::PATH)?.write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use ggez::{
+    graphics::{self, Rect}, Context, GameResult,
+    timer,
+};
+
+use sdl2::keyboard::{Keycode, Scancode, Mod};
+
+use specs::{Builder, Dispatcher, DispatcherBuilder, Join, RunNow, World};
+
+use super::{GameState, StateTransition};
+use audio::Audio;
+use components::{DeltaTime, register_components, Pos, Sprite, Vel};
+use resources::Resources;
+use systems::{KinematicSystem, SpriteRenderSystem};
+
+/// Stress test for the sprite renderer: a few thousand asteroids drifting through the tunnel.
+/// Press `B` to toggle sprite batching; the frame rate is printed once per second.
+pub struct BenchmarkState {
+    world: World,
+    dispatcher: Dispatcher<'static, 'static>,
+    quit: bool,
+    batched: bool,
+    update_time_remaining: Duration,
+    report_time_remaining: Duration,
+}
+
+impl BenchmarkState {
+    pub fn new(ctx: &mut Context, audio: &Audio, n_asteroids: usize) -> GameResult<Self> {
+        let mut world = World::new();
+
+        register_components(&mut world, audio.clone());
+
+        world.write_resource::<DeltaTime>().0 = Duration::from_nanos(1_000_000_000 / 60);
+
+        // the manifest packs the asteroids into an atlas, so `add_image` below resolves to atlas regions
+        world.write_resource::<Resources>().load_manifest(ctx, "/assets.toml")?;
+
+        let sprites = [
+            world.write_resource::<Resources>().add_image(ctx, "/originals/asteroids/large/a10000.png")?,
+            world.write_resource::<Resources>().add_image(ctx, "/originals/asteroids/large/b10000.png")?,
+            world.write_resource::<Resources>().add_image(ctx, "/originals/asteroids/large/c10000.png")?,
+        ];
+
+        for i in 0..n_asteroids {
+            // cheap deterministic scattering, good enough to fill the tunnel
+            let w = (i as f32 * 0.618_034) % 1.0;
+            let r = 0.2 + (i as f32 * 0.414_214) % 0.8;
+            let z = (i as f32 * 0.732_051) % 20.0;
+
+            world.create_entity()
+                .with(Pos::new(r, w, z))
+                .with(Vel::new(0.0, 0.05, -1.0))
+                .with(Sprite::new_auto(sprites[i % sprites.len()], 0.3))
+                .build();
+        }
+
+        let dispatcher = DispatcherBuilder::new()
+            .with(KinematicSystem, "kinematics", &[])
+            .build();
+
+        let s = BenchmarkState {
+            world,
+            dispatcher,
+            quit: false,
+            batched: true,
+            update_time_remaining: Duration::from_secs(0),
+            report_time_remaining: Duration::from_secs(1),
+        };
+        Ok(s)
+    }
+}
+
+impl GameState for BenchmarkState {
+    fn transition(&mut self) -> StateTransition {
+        if self.quit {
+            StateTransition::Pop
+        } else {
+            StateTransition::None
+        }
+    }
+
+    fn update(&mut self, ctx: &mut Context) -> GameResult<bool> {
+        let update_time = self.world.read_resource::<DeltaTime>().0;
+
+        self.update_time_remaining += timer::get_delta(ctx);
+        while self.update_time_remaining >= update_time {
+            self.update_time_remaining -= update_time;
+
+            self.dispatcher.dispatch(&self.world.res);
+            self.world.maintain();
+
+            // recycle asteroids that passed the camera
+            for p in (&mut self.world.write_storage::<Pos>()).join() {
+                if p.0.z < 0.0 {
+                    p.0.z += 20.0;
+                }
+            }
+        }
+
+        if self.report_time_remaining > timer::get_delta(ctx) {
+            self.report_time_remaining -= timer::get_delta(ctx);
+        } else {
+            self.report_time_remaining = Duration::from_secs(1);
+            println!("{} fps ({})", timer::get_fps(ctx).round(), if self.batched { "batched" } else { "unbatched" });
+        }
+
+        Ok(false)
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        graphics::clear(ctx);
+        graphics::set_screen_coordinates(ctx, Rect::new(-2.0, -1.5, 4.0, 3.0))?;
+
+        if self.batched {
+            SpriteRenderSystem::new(ctx).run_now(&self.world.res);
+        } else {
+            SpriteRenderSystem::unbatched(ctx).run_now(&self.world.res);
+        }
+
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, scancode: Scancode, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
+        match scancode {
+            Scancode::Escape => self.quit = true,
+            Scancode::B => self.batched = !self.batched,
+            _ => {}
+        }
+        false
+    }
+}
@@ -0,0 +1,68 @@
+use ggez::{
+    graphics::{self, Color, DrawMode, Point2, Rect}, Context, GameResult,
+};
+
+use sdl2::keyboard::{Keycode, Scancode, Mod};
+
+use super::{GameState, StateTransition};
+use audio::MusicCue;
+
+/// Shown over the frozen game once the player's ship is destroyed. Returns to the title screen below it.
+pub struct GameOverState {
+    text: graphics::TextCached,
+    leave: bool,
+}
+
+impl GameOverState {
+    pub fn new() -> GameResult<Self> {
+        let s = GameOverState {
+            text: graphics::TextCached::new("GAME OVER - press space to return to the title screen")?,
+            leave: false,
+        };
+        Ok(s)
+    }
+}
+
+impl GameState for GameOverState {
+    fn transition(&mut self) -> StateTransition {
+        if self.leave {
+            // this state and the game below it
+            StateTransition::PopN(2)
+        } else {
+            StateTransition::None
+        }
+    }
+
+    fn update(&mut self, _ctx: &mut Context) -> GameResult<bool> {
+        Ok(false)
+    }
+
+    fn music(&self) -> Option<MusicCue> {
+        Some(MusicCue::new("gameover", 0.0))
+    }
+
+    fn draw_previous(&self) -> bool {
+        true
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let (w, h) = graphics::get_size(ctx);
+        graphics::set_screen_coordinates(ctx, Rect::new(0.0, 0.0, w as f32, h as f32))?;
+
+        graphics::set_color(ctx, Color::new(0.0, 0.0, 0.0, 0.5))?;
+        graphics::rectangle(ctx, DrawMode::Fill, Rect::new(0.0, 0.0, w as f32, h as f32))?;
+
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+        let x = (w as f32 - self.text.width(ctx) as f32) * 0.5;
+        graphics::draw(ctx, &self.text, Point2::new(x, h as f32 * 0.5), 0.0)?;
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, scancode: Scancode, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
+        match scancode {
+            Scancode::Space | Scancode::Return | Scancode::Escape => self.leave = true,
+            _ => {}
+        }
+        false
+    }
+}
@@ -0,0 +1,75 @@
+use ggez::{
+    graphics::{self, Point2, Rect}, Context, GameResult,
+};
+
+use sdl2::keyboard::{Keycode, Scancode, Mod};
+
+use super::{GameState, StateTransition, wormhole::WormholeState};
+use audio::{Audio, MusicCue};
+
+/// Title screen. Starts a new game, and the game returns here when it is quit.
+pub struct MenuState {
+    audio: Audio,
+    dev_mode: bool,
+    text: graphics::TextCached,
+    start: bool,
+    quit: bool,
+    next_state: Option<Box<GameState>>,
+}
+
+impl MenuState {
+    pub fn new(_ctx: &mut Context, audio: &Audio, dev_mode: bool) -> GameResult<Self> {
+        let s = MenuState {
+            audio: audio.clone(),
+            dev_mode,
+            text: graphics::TextCached::new("KERR - press space to start, escape to quit")?,
+            start: false,
+            quit: false,
+            next_state: None,
+        };
+        Ok(s)
+    }
+}
+
+impl GameState for MenuState {
+    fn transition(&mut self) -> StateTransition {
+        if self.quit {
+            StateTransition::Pop
+        } else if let Some(state) = self.next_state.take() {
+            StateTransition::Push(state)
+        } else {
+            StateTransition::None
+        }
+    }
+
+    fn update(&mut self, ctx: &mut Context) -> GameResult<bool> {
+        if self.start {
+            self.start = false;
+            self.next_state = Some(Box::new(WormholeState::new(ctx, &self.audio, self.dev_mode)?));
+        }
+        Ok(false)
+    }
+
+    fn music(&self) -> Option<MusicCue> {
+        Some(MusicCue::new("menu", 0.0))
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let (w, h) = graphics::get_size(ctx);
+        graphics::set_screen_coordinates(ctx, Rect::new(0.0, 0.0, w as f32, h as f32))?;
+
+        graphics::clear(ctx);
+        let x = (w as f32 - self.text.width(ctx) as f32) * 0.5;
+        graphics::draw(ctx, &self.text, Point2::new(x, h as f32 * 0.5), 0.0)?;
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, scancode: Scancode, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
+        match scancode {
+            Scancode::Space | Scancode::Return => self.start = true,
+            Scancode::Escape => self.quit = true,
+            _ => {}
+        }
+        false
+    }
+}
@@ -1,21 +1,33 @@
-pub mod hello;
+pub mod benchmark;
+pub mod gameover;
+pub mod menu;
+pub mod options;
 pub mod wormhole;
 
 use ggez::{
-    event::{EventHandler, Keycode, Mod}, Context, GameResult,
+    event::{EventHandler, Keycode, Mod}, graphics, Context, GameResult,
 };
 
 use sdl2::keyboard::Scancode;
 
+use audio::{Music, MusicCue};
+
 pub trait GameState {
-    fn transition(&self) -> StateTransition {
+    fn transition(&mut self) -> StateTransition {
         StateTransition::None
     }
 
     /// update. return `Ok(true)` if the state below should be updated too.
     fn update(&mut self, ctx: &mut Context) -> GameResult<bool>;
+
+    /// draw the state. The state manager presents the frame once all states are drawn.
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()>;
 
+    /// the music this state wants to hear. `None` leaves the choice to the state below.
+    fn music(&self) -> Option<MusicCue> {
+        None
+    }
+
     /// override if this state is not completely opaque and the previous state should be drawn too
     fn draw_previous(&self) -> bool {
         false
@@ -53,15 +65,23 @@ pub enum StateTransition {
 
 pub struct StateManager {
     states: Vec<Box<GameState>>,
+    music: Option<Music>,
 }
 
 impl StateManager {
     pub fn new<T: GameState + 'static>(initial_state: T) -> Self {
         StateManager {
             states: vec![Box::new(initial_state)],
+            music: None,
         }
     }
 
+    /// let the states choose the music; it crossfades when the topmost state's cue changes
+    pub fn with_music(mut self, music: Music) -> Self {
+        self.music = Some(music);
+        self
+    }
+
     pub fn quit_event(&mut self, _ctx: &mut Context) -> bool {
         false
     }
@@ -107,7 +127,7 @@ impl StateManager {
             }
         }
 
-        match self.states.last().unwrap().transition() {
+        match self.states.last_mut().unwrap().transition() {
             StateTransition::None => {}
             StateTransition::Pop => {
                 self.states.pop();
@@ -119,11 +139,17 @@ impl StateManager {
             StateTransition::Push(bs) => self.states.push(bs),
         }
 
+        if let Some(ref mut music) = self.music {
+            music.update(self.states.iter().rev().filter_map(|s| s.music()).next());
+        }
+
         Ok(())
     }
 
     pub fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
-        StateManager::draw_recursive(&mut self.states, ctx)
+        StateManager::draw_recursive(&mut self.states, ctx)?;
+        graphics::present(ctx);
+        Ok(())
     }
 
     fn draw_recursive(states: &mut [Box<GameState>], ctx: &mut Context) -> GameResult<()> {
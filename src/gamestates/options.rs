@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use ggez::{
+    graphics::{self, Color, DrawMode, Point2, Rect}, Context, GameResult,
+};
+
+use rodio::dynamic_mixer::DynamicMixerController;
+
+use sdl2::keyboard::{Keycode, Scancode, Mod};
+
+use super::{GameState, StateTransition};
+use audio::{Audio, Bus, Buses, Sample};
+use config::Config;
+
+/// Overlay menu for the audio volumes. Changes are applied immediately and saved when the menu is closed.
+pub struct OptionsState {
+    buses: Arc<Buses>,
+    ui_mixer: Arc<DynamicMixerController<f32>>,
+    click: Sample,
+    selected: usize,
+    close: bool,
+    closed: bool,
+}
+
+impl OptionsState {
+    const STEP: f32 = 0.1;
+
+    pub fn new(audio: &Audio, click: Sample) -> Self {
+        OptionsState {
+            buses: audio.buses.clone(),
+            ui_mixer: audio.ui_mixer.clone(),
+            click,
+            selected: 0,
+            close: false,
+            closed: false,
+        }
+    }
+
+    fn click(&self) {
+        self.ui_mixer.add(self.click.source(false, None));
+    }
+}
+
+impl GameState for OptionsState {
+    fn transition(&mut self) -> StateTransition {
+        if self.closed {
+            StateTransition::Pop
+        } else {
+            StateTransition::None
+        }
+    }
+
+    fn update(&mut self, ctx: &mut Context) -> GameResult<bool> {
+        if self.close && !self.closed {
+            let mut config = Config::load(ctx);
+            self.buses.to_config(&mut config.audio);
+            if let Err(e) = config.save(ctx) {
+                println!("could not save settings: {}", e);
+            }
+            self.closed = true;
+        }
+        Ok(false)
+    }
+
+    fn draw_previous(&self) -> bool {
+        true
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let (w, h) = graphics::get_size(ctx);
+        graphics::set_screen_coordinates(ctx, Rect::new(0.0, 0.0, w as f32, h as f32))?;
+
+        graphics::set_color(ctx, Color::new(0.0, 0.0, 0.0, 0.7))?;
+        graphics::rectangle(ctx, DrawMode::Fill, Rect::new(0.0, 0.0, w as f32, h as f32))?;
+
+        for (i, &bus) in Bus::ALL.iter().enumerate() {
+            let line = if self.buses.is_muted(bus) {
+                format!("{:<10} muted", bus.name())
+            } else {
+                format!("{:<10} {:3.0}%", bus.name(), self.buses.volume(bus) * 100.0)
+            };
+
+            if i == self.selected {
+                graphics::set_color(ctx, Color::new(1.0, 0.8, 0.2, 1.0))?;
+            } else {
+                graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+            }
+
+            let text = graphics::TextCached::new(line)?;
+            graphics::draw(ctx, &text, Point2::new(w as f32 * 0.4, h as f32 * 0.4 + i as f32 * 30.0), 0.0)?;
+        }
+
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, scancode: Scancode, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
+        let bus = Bus::ALL[self.selected];
+        match scancode {
+            Scancode::Escape | Scancode::O => self.close = true,
+            Scancode::Up | Scancode::W => self.selected = (self.selected + Bus::ALL.len() - 1) % Bus::ALL.len(),
+            Scancode::Down | Scancode::S => self.selected = (self.selected + 1) % Bus::ALL.len(),
+            Scancode::Left | Scancode::A => self.buses.set_volume(bus, self.buses.volume(bus) - OptionsState::STEP),
+            Scancode::Right | Scancode::D => self.buses.set_volume(bus, self.buses.volume(bus) + OptionsState::STEP),
+            Scancode::M | Scancode::Return => self.buses.set_muted(bus, !self.buses.is_muted(bus)),
+            _ => return false,
+        }
+        self.click();
+        false
+    }
+
+    /// keys held when the menu opened are released in the game below, or they would stay pressed there
+    fn key_up_event(&mut self, _scancode: Scancode, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
+        true
+    }
+}
@@ -1,10 +1,12 @@
+use std::cmp::Ordering;
 use std::f32;
+use std::path::Path;
 use std::time::Duration;
 
 use ambisonic::{Ambisonic, AmbisonicBuilder, sources::Noise};
 
 use ggez::{
-    graphics::{self, DrawMode, MeshBuilder, Point2, Rect}, Context,
+    graphics::{self, Color, DrawMode, MeshBuilder, Point2, Rect}, Context,
     GameResult,
     timer,
 };
@@ -13,38 +15,86 @@ use rodio::{self, Source};
 
 use sdl2::keyboard::{Keycode, Scancode, Mod};
 
-use specs::{Builder, Dispatcher, DispatcherBuilder, RunNow, World};
+use specs::{Builder, Dispatcher, DispatcherBuilder, Join, RunNow, World};
 
-use super::{GameState, StateTransition};
-use audio::Audio;
-use components::{Acc, Controlled, DeltaTime, register_components, Pos, RocketLauncher, SoundEmitter, Sprite, Vel};
+use super::{GameState, StateTransition, gameover::GameOverState, options::OptionsState};
+use audio::{Audio, MusicCue};
+use components::{Acc, Animated, Boss, Camera, Controlled, DeltaTime, Enemy, Energy, Health, Lane, Launcher, Obstacle, register_components, Pos, SoundEmitter, Sprite, Tunnel, Vel};
 use inputstate::InputState;
-use resources::Resources;
-use systems::{InputSystem, KinematicSystem, RocketLauncherSystem, RocketProjectileSystem, SpatialAudioSystem, SpriteRenderSystem};
-use three_dee::projection_factor;
+use level::Level;
+use resources::{ImageId, Resources, SoundId};
+use systems::{AnimationSystem, CameraSystem, EnvironmentSystem, GravitySystem, HomingSystem, InputSystem, KinematicSystem, LauncherSystem, LockOnRenderSystem, ObstacleRenderSystem, ObstacleSystem, ParticleRenderSystem, ParticleSystem, ProjectileSystem, ShipCollisionSystem, SpatialAudioSystem, SpriteRenderSystem, TargetingSystem, ThrottleSystem, VoiceLimitSystem};
+use three_dee::Cylindric;
+use weapons::Weapons;
+
+const WEAPONS_FILE: &str = "/weapons.toml";
+
+/// number of points per tunnel ring; divisible by the number of sides of common polygons
+const RING_POINTS: usize = 96;
 
 pub struct WormholeState {
     world: World,
     dispatcher: Dispatcher<'static, 'static>,
     quit: bool,
+    open_options: bool,
+    /// set once the game over screen has been pushed
+    game_over: bool,
+    next_state: Option<Box<GameState>>,
+    click_sound: SoundId,
+    particle_sprite: ImageId,
+    /// real time between updates
+    update_time: Duration,
     update_time_remaining: Duration,
-    z_pos: f32,
 }
 
 impl WormholeState {
-    pub fn new(ctx: &mut Context) -> GameResult<Self> {
+    /// In `dev_mode` changed asset files are reloaded while the game is running.
+    pub fn new(ctx: &mut Context, audio: &Audio, dev_mode: bool) -> GameResult<Self> {
         let mut world = World::new();
 
-        register_components(&mut world);
+        register_components(&mut world, audio.clone());
+
+        let update_time = Duration::from_nanos(1_000_000_000 / 60);  // update at 60 fps
+        world.write_resource::<DeltaTime>().0 = update_time;
+
+        world.write_resource::<Resources>().load_manifest(ctx, "/assets.toml")?;
+        if dev_mode {
+            world.write_resource::<Resources>().enable_hot_reload();
+            world.write_resource::<Resources>().watch_data(WEAPONS_FILE);
+        }
 
-        world.write_resource::<DeltaTime>().0 = Duration::from_nanos(1_000_000_000 / 60);  // update at 60 fps
+        {
+            let res = world.read_resource::<Resources>();
+            world.write_resource::<Weapons>().load(ctx, &res, WEAPONS_FILE)?;
+        }
 
-        let player_sprite = world.write_resource::<Resources>().add_image(ctx, "/ship_perspective.png")?;
+        let level = Level::load(ctx, "/levels/wormhole.toml")?;
+        let ship_handling = level.ship_handling(ctx, "/ships.toml")?;
+        world.write_resource::<Camera>().path = level.tunnel.path.clone();
+        let mut tunnel = level.tunnel;
+        tunnel.current_speed = tunnel.speed;
+        world.add_resource(tunnel);
+        world.add_resource(level.environment);
+        world.add_resource(level.gravity);
+
+        for obstacle in level.obstacles {
+            world.create_entity()
+                .with(Pos::new(1.0, obstacle.from, obstacle.at))
+                .with(obstacle)
+                .build();
+        }
 
-        let asteroid_sprite = world.write_resource::<Resources>().add_image(ctx, "/originals/asteroids/large/a10000.png")?;
-        let quad_sprite = world.write_resource::<Resources>().add_image(ctx, "/40x40.png")?;
+        let (player_sprite, asteroid_animation, particle_sprite, click_sound) = {
+            let res = world.read_resource::<Resources>();
+            (res.image("ship")?, res.animation("asteroid")?, res.image("particle")?, res.sound("click")?)
+        };
 
-        let rocket_sprite = world.write_resource::<Resources>().add_image(ctx, "/rocket.png")?;
+        let launcher = {
+            let weapons = world.read_resource::<Weapons>();
+            let ids = level.weapons.iter().map(|w| weapons.weapon(w)).collect::<GameResult<Vec<_>>>()?;
+            Launcher::new(ids)
+        };
+        let asteroid_sprite = world.read_resource::<Resources>().get_animation(asteroid_animation).frames[0];
 
         //let se = SoundEmitter::new(&world.read_resource::<Audio>().ambisonic);
         //se.mixer_controller.add(Noise::new(48000));
@@ -52,7 +102,11 @@ impl WormholeState {
         world.create_entity()
             .with(Pos::new(0.2, 0.25, 100.0))
             .with(Vel::new(0.0, 0.0, -10.0))
-            .with(Sprite::new_auto(asteroid_sprite, 1.0))
+            .with(Sprite::new_auto(asteroid_sprite, 2.0))
+            .with(Animated::new(asteroid_animation))
+            .with(Health::new(200.0))
+            .with(Enemy)
+            .with(Boss)
             //.with(se)
             .build();
 
@@ -61,7 +115,11 @@ impl WormholeState {
             .with(Pos::new(1.0, 0.0, 2.2))
             .with(Vel::new(0.0, 0.0, 0.0))
             .with(Sprite::new_fixed(player_sprite, 0.5, 0.25))
-            .with(RocketLauncher::Ready)
+            .with(launcher)
+            .with(Health::new(100.0))
+            .with(Energy::new(100.0))
+            .with(ship_handling)
+            .with(Lane(0))
             //.with(se)
             .with(Controlled)
             .build();
@@ -71,6 +129,9 @@ impl WormholeState {
             .with(Pos::new(1.0, 3.0, 5.0))
             .with(Vel::new(0.0, 0.1, -0.3))
             .with(Sprite::new_fixed(asteroid_sprite, 1.0, 1.0))
+            .with(Animated::new(asteroid_animation))
+            .with(Health::new(40.0))
+            .with(Enemy)
             .build();
 
         /*world
@@ -90,35 +151,101 @@ impl WormholeState {
             .build();*/
 
         let dispatcher = DispatcherBuilder::new()
-            .with(InputSystem, "input", &[])
-            .with(RocketLauncherSystem, "rocket_launcher", &["input"])
-            .with(RocketProjectileSystem, "rocket projectile", &[])
-            .with(KinematicSystem, "kinematics", &["input"])
+            .with(InputSystem::default(), "input", &[])
+            .with(TargetingSystem, "targeting", &["input"])
+            .with(LauncherSystem, "launcher", &["input", "targeting"])
+            .with(ProjectileSystem, "projectiles", &[])
+            .with(HomingSystem, "homing", &["projectiles"])
+            .with(GravitySystem, "gravity", &["input"])
+            .with(ThrottleSystem, "throttle", &[])
+            .with(KinematicSystem, "kinematics", &["input", "gravity", "throttle", "homing"])
+            .with(ShipCollisionSystem::default(), "ship collision", &["kinematics"])
+            .with(ParticleSystem, "particles", &["kinematics", "projectiles", "ship collision"])
             .with(SpatialAudioSystem, "spatial audio", &["kinematics"])
+            .with(VoiceLimitSystem, "voice limit", &["spatial audio"])
+            .with(AnimationSystem, "animation", &[])
+            .with(CameraSystem, "camera", &["kinematics", "ship collision"])
+            .with(ObstacleSystem, "obstacles", &["camera"])
+            .with(EnvironmentSystem, "environment", &[])
             .build();
 
         let s = WormholeState {
             world,
             dispatcher,
-            z_pos: 0.0,
+            update_time,
             update_time_remaining: Duration::from_secs(0),
             quit: false,
+            open_options: false,
+            game_over: false,
+            next_state: None,
+            click_sound,
+            particle_sprite,
         };
         Ok(s)
     }
+
+    fn player_destroyed(&self) -> bool {
+        (&self.world.read_storage::<Controlled>(), &self.world.read_storage::<Health>()).join()
+            .any(|(_, h)| h.current <= 0.0)
+    }
 }
 
 impl GameState for WormholeState {
-    fn transition(&self) -> StateTransition {
+    fn transition(&mut self) -> StateTransition {
         if self.quit {
             StateTransition::Pop
+        } else if !self.game_over && self.player_destroyed() {
+            self.game_over = true;
+            match GameOverState::new() {
+                Ok(state) => StateTransition::Push(Box::new(state)),
+                Err(e) => {
+                    println!("could not show the game over screen: {}", e);
+                    StateTransition::Pop
+                }
+            }
+        } else if let Some(state) = self.next_state.take() {
+            StateTransition::Push(state)
         } else {
             StateTransition::None
         }
     }
 
+    /// more enemies and less health make the music more intense; a boss ahead switches to the boss track
+    fn music(&self) -> Option<MusicCue> {
+        let enemies = self.world.read_storage::<Enemy>().join().count();
+        let damage = (&self.world.read_storage::<Controlled>(), &self.world.read_storage::<Health>()).join()
+            .next()
+            .map_or(0.0, |(_, h)| 1.0 - h.fraction());
+        let intensity = (enemies as f32 / 10.0).min(1.0) * 0.7 + damage * 0.3;
+
+        let camera_z = self.world.read_resource::<Camera>().z;
+        let boss_ahead = (&self.world.read_storage::<Boss>(), &self.world.read_storage::<Pos>()).join()
+            .any(|(_, p)| p.0.z >= camera_z);
+
+        if boss_ahead {
+            Some(MusicCue::new("boss", damage))
+        } else {
+            Some(MusicCue::new("gameplay", intensity))
+        }
+    }
+
     fn update(&mut self, ctx: &mut Context) -> GameResult<bool> {
-        let update_time = self.world.read_resource::<DeltaTime>().0;
+        let update_time = self.update_time;
+
+        let changed = self.world.write_resource::<Resources>().reload_changed(ctx);
+        if changed.iter().any(|f| f == Path::new(WEAPONS_FILE)) {
+            let res = self.world.read_resource::<Resources>();
+            match self.world.write_resource::<Weapons>().load(ctx, &res, WEAPONS_FILE) {
+                Ok(()) => println!("reloaded {}", WEAPONS_FILE),
+                Err(e) => println!("failed to reload {}: {}", WEAPONS_FILE, e),
+            }
+        }
+
+        if self.open_options {
+            self.open_options = false;
+            let click = self.world.read_resource::<Resources>().get_sound(self.click_sound).clone();
+            self.next_state = Some(Box::new(OptionsState::new(&self.world.read_resource::<Audio>(), click)));
+        }
 
         self.update_time_remaining += timer::get_delta(ctx);
         while self.update_time_remaining >= update_time {
@@ -129,11 +256,6 @@ impl GameState for WormholeState {
 
             self.dispatcher.dispatch(&self.world.res);
             self.world.maintain();
-
-            self.z_pos -= (1.0 * timer::duration_to_f64(update_time)) as f32;
-            while self.z_pos <= 0.0 {
-                self.z_pos += 2.0;
-            }
         }
 
         Ok(false)
@@ -143,19 +265,24 @@ impl GameState for WormholeState {
         graphics::clear(ctx);
         graphics::set_screen_coordinates(ctx, Rect::new(-2.0, -1.5, 4.0, 3.0))?;
 
+        let radius = self.world.read_resource::<Tunnel>().radius;
+        let camera = self.world.read_resource::<Camera>();
+
         let mut mb = MeshBuilder::new();
 
+        // the rings are one unit apart and move towards the camera as it flies on
+        let scroll = 1.0 - camera.distance % 1.0;
         for z in 0..20 {
-            let z = 0.1 + (z as f32 + self.z_pos % 1.0) * 1.0;
+            let z = camera.z + 0.1 + (z as f32 + scroll) * 1.0;
 
-            let p = projection_factor(z);
+            let p = camera.scale(z);
 
-            mb.circle(
-                DrawMode::Line((0.03 * p).max(0.01)),
-                Point2::new(0.0, 0.0) * p,
-                1.0 * p,
-                0.001,
-            );
+            // trace the ring through the camera so it follows the bends and the shape of the tunnel
+            let points: Vec<Point2> = (0..RING_POINTS)
+                .map(|i| camera.project(Cylindric::new(radius, i as f32 / RING_POINTS as f32, z)).into())
+                .collect();
+
+            mb.polygon(DrawMode::Line((0.03 * p).max(0.01)), &points);
         }
 
         /*let p1 = projection_factor(self.z_pos * 10.0 );
@@ -174,18 +301,51 @@ impl GameState for WormholeState {
 
         graphics::draw(ctx, &mesh, Point2::new(0.0, 0.0), 0.0)?;
 
+        // Obstacles and sprites are drawn from back to front: all sprites behind an obstacle, then the
+        // obstacle, and so on. Obstacles are few, so the extra sprite batches are cheap.
+        let mut depths: Vec<f32> = (&self.world.read_storage::<Pos>(), &self.world.read_storage::<Obstacle>()).join()
+            .map(|(p, _)| p.0.z)
+            .collect();
+        depths.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        depths.dedup();
+
         // too bad we cannot use the dispatcher for the rendering systems...
         {
-            SpriteRenderSystem::new(ctx).run_now(&self.world.res);
+            let mut far = f32::INFINITY;
+            for &near in &depths {
+                SpriteRenderSystem::new(ctx).in_depth_range(near, far).run_now(&self.world.res);
+                ObstacleRenderSystem::new(ctx).in_depth_range(near, far).run_now(&self.world.res);
+                far = near;
+            }
+            SpriteRenderSystem::new(ctx).in_depth_range(f32::NEG_INFINITY, far).run_now(&self.world.res);
+            ParticleRenderSystem::new(ctx, self.particle_sprite).run_now(&self.world.res);
+            LockOnRenderSystem::new(ctx).run_now(&self.world.res);
+        }
+
+        // energy gauge in the bottom left corner; red while the boost is locked out
+        let energy = (&self.world.read_storage::<Controlled>(), &self.world.read_storage::<Energy>()).join()
+            .next()
+            .map(|(_, e)| (e.fraction(), e.depleted));
+        if let Some((fraction, depleted)) = energy {
+            let color = if depleted { Color::new(1.0, 0.3, 0.2, 0.8) } else { Color::new(0.3, 0.8, 1.0, 0.8) };
+            graphics::set_color(ctx, color)?;
+            graphics::rectangle(ctx, DrawMode::Fill, Rect::new(-1.9, 1.35, 0.8 * fraction, 0.05))?;
+            graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
         }
 
-        graphics::present(ctx);
         Ok(())
     }
 
     fn key_down_event(&mut self, scancode: Scancode, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
-        if scancode == Scancode::Escape {
-            self.quit = true;
+        match scancode {
+            Scancode::Escape => self.quit = true,
+            Scancode::O => self.open_options = true,
+            Scancode::P => {
+                let mut camera = self.world.write_resource::<Camera>();
+                let next = camera.projection_kind().next();
+                camera.set_projection(next);
+            }
+            _ => {}
         }
 
         self.world.write_resource::<InputState>().set(scancode);
@@ -14,7 +14,19 @@ pub enum Input {
     Left,
     Right,
 
+    /// move to the next lane towards the tunnel axis
+    Inner,
+    /// move to the next lane towards the tunnel wall
+    Outer,
+
+    /// fly faster, using up energy
+    Boost,
+    /// fly slower
+    Brake,
+
     Fire,
+    /// switch to the next weapon
+    NextWeapon,
 }
 
 pub struct InputState {
@@ -35,7 +47,14 @@ impl Default for InputState {
         mapping.insert(Scancode::A, Input::Left);
         mapping.insert(Scancode::D, Input::Right);
 
+        mapping.insert(Scancode::Q, Input::Inner);
+        mapping.insert(Scancode::E, Input::Outer);
+
+        mapping.insert(Scancode::LShift, Input::Boost);
+        mapping.insert(Scancode::LCtrl, Input::Brake);
+
         mapping.insert(Scancode::Space, Input::Fire);
+        mapping.insert(Scancode::Tab, Input::NextWeapon);
 
         InputState {
             inputs: [false; 256],
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use ggez::{Context, GameError, GameResult};
+
+use toml;
+
+use audio::EnvironmentDef;
+use components::{GravityField, Obstacle, ShipHandling, Tunnel};
+
+/// Everything that distinguishes one level from another, loaded from a TOML file.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Level {
+    pub tunnel: Tunnel,
+    pub environment: EnvironmentDef,
+    pub gravity: GravityField,
+    pub obstacles: Vec<Obstacle>,
+    /// ship type of the player, from the ship definitions
+    pub ship: String,
+    /// weapons the player can switch between, from the weapon definitions
+    pub weapons: Vec<String>,
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Level {
+            tunnel: Tunnel::default(),
+            environment: EnvironmentDef::default(),
+            gravity: GravityField::default(),
+            obstacles: Vec::new(),
+            ship: "fighter".to_string(),
+            weapons: vec!["rocket".to_string()],
+        }
+    }
+}
+
+impl Level {
+    pub fn load(ctx: &mut Context, path: &str) -> GameResult<Level> {
+        let mut text = String::new();
+        ctx.filesystem.open(path)?.read_to_string(&mut text)?;
+
+        toml::from_str(&text).map_err(|e| GameError::ResourceLoadError(format!("{}: {}", path, e)))
+    }
+
+    /// look up the handling of the player's ship in the ship definitions file at `path`
+    pub fn ship_handling(&self, ctx: &mut Context, path: &str) -> GameResult<ShipHandling> {
+        let mut text = String::new();
+        ctx.filesystem.open(path)?.read_to_string(&mut text)?;
+
+        let mut ships: HashMap<String, ShipHandling> = toml::from_str(&text)
+            .map_err(|e| GameError::ResourceLoadError(format!("{}: {}", path, e)))?;
+
+        ships.remove(&self.ship)
+            .ok_or_else(|| GameError::ResourceLoadError(format!("{}: unknown ship type '{}'", path, self.ship)))
+    }
+}
@@ -2,21 +2,34 @@ use std::time::Duration;
 
 extern crate ambisonic;
 extern crate ggez;
+extern crate image;
+extern crate rand;
 extern crate rodio;
 extern crate sdl2;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate specs;
 #[macro_use]
 extern crate specs_derive;
 extern crate steamy_controller;
+extern crate toml;
 
+mod atlas;
 mod audio;
+mod camera;
 mod components;
+mod config;
 mod gamestates;
 mod inputstate;
+mod level;
+mod particles;
 mod resources;
 mod systems;
 mod three_dee;
+mod tunnel;
 mod utils;
+mod weapons;
 
 use ggez::{
     conf, event::{Event, EventHandler, Events}, GameResult,
@@ -24,7 +37,9 @@ use ggez::{
 
 use steamy_controller::Manager;
 
-use gamestates::{wormhole::WormholeState, StateManager};
+use audio::{Audio, Music};
+use config::Config;
+use gamestates::{benchmark::BenchmarkState, menu::MenuState, StateManager};
 
 fn main() -> GameResult<()> {
     let c = conf::Conf {
@@ -35,7 +50,22 @@ fn main() -> GameResult<()> {
 
     let ctx = &mut ggez::Context::load_from_conf("Kerr", "Swampsoft Games", c).unwrap();
 
-    let states = &mut StateManager::new(WormholeState::new(ctx)?);
+    let config = Config::load(ctx);
+
+    let mut audio = Audio::default();
+    audio.apply_config(&config.audio);
+
+    let mut music = Music::new(&audio);
+    music.load(ctx, "/music.toml")?;
+
+    let mut args = std::env::args().skip_while(|a| a != "--benchmark");
+    let states = &mut if args.next().is_some() {
+        let n = args.next().and_then(|n| n.parse().ok()).unwrap_or(5000);
+        StateManager::new(BenchmarkState::new(ctx, &audio, n)?)
+    } else {
+        let dev_mode = std::env::args().any(|a| a == "--dev");
+        StateManager::new(MenuState::new(ctx, &audio, dev_mode)?).with_music(music)
+    };
 
     /*let mut scm = Manager::new().unwrap();
     let mut ctr = scm.open().unwrap();
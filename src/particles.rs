@@ -0,0 +1,178 @@
+use std::f32::consts::PI;
+
+use ggez::graphics::Color;
+
+use rand::{rngs::SmallRng, FromEntropy, Rng};
+
+use three_dee::Cylindric;
+
+/// Upper limit on the number of live particles. New particles are dropped while the pool is full.
+pub const MAX_PARTICLES: usize = 8192;
+
+/// How the particles of an effect look and move. Speeds are in world units per second.
+#[derive(Debug, Copy, Clone)]
+pub struct ParticleEffect {
+    /// seconds a particle lives
+    pub lifetime: f32,
+    /// random extra lifetime, up to this many seconds
+    pub lifetime_spread: f32,
+    /// velocity added to every particle, in addition to a share of the emitter's velocity
+    pub velocity: Cylindric,
+    /// how much of the emitter's velocity the particles keep
+    pub inherit: f32,
+    /// particles fly off in a random direction with up to this speed
+    pub speed: f32,
+    pub size: f32,
+    pub color: Color,
+}
+
+impl ParticleEffect {
+    /// hot gas pushed out behind an accelerating rocket
+    pub const EXHAUST: ParticleEffect = ParticleEffect {
+        lifetime: 0.25,
+        lifetime_spread: 0.15,
+        velocity: Cylindric { r: 0.0, w: 0.0, z: -2.0 },
+        inherit: 0.5,
+        speed: 0.3,
+        size: 0.06,
+        color: Color { r: 1.0, g: 0.6, b: 0.2, a: 1.0 },
+    };
+
+    /// rock fragments of a destroyed asteroid
+    pub const DEBRIS: ParticleEffect = ParticleEffect {
+        lifetime: 0.8,
+        lifetime_spread: 0.6,
+        velocity: Cylindric { r: 0.0, w: 0.0, z: 0.0 },
+        inherit: 1.0,
+        speed: 1.5,
+        size: 0.08,
+        color: Color { r: 0.7, g: 0.6, b: 0.5, a: 1.0 },
+    };
+
+    /// fireball of an exploding rocket
+    pub const EXPLOSION: ParticleEffect = ParticleEffect {
+        lifetime: 0.35,
+        lifetime_spread: 0.25,
+        velocity: Cylindric { r: 0.0, w: 0.0, z: 0.0 },
+        inherit: 0.3,
+        speed: 1.2,
+        size: 0.12,
+        color: Color { r: 1.0, g: 0.5, b: 0.1, a: 1.0 },
+    };
+
+    /// short-lived sparks where the ship is hit
+    pub const SPARKS: ParticleEffect = ParticleEffect {
+        lifetime: 0.2,
+        lifetime_spread: 0.2,
+        velocity: Cylindric { r: 0.0, w: 0.0, z: 0.0 },
+        inherit: 1.0,
+        speed: 2.5,
+        size: 0.03,
+        color: Color { r: 1.0, g: 0.9, b: 0.5, a: 1.0 },
+    };
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Particle {
+    pub pos: Cylindric,
+    pub vel: Cylindric,
+    pub age: f32,
+    pub lifetime: f32,
+    pub size: f32,
+    pub color: Color,
+}
+
+impl Particle {
+    /// fraction of the lifetime that is left, from 1 when spawned to 0 when it dies
+    pub fn remaining(&self) -> f32 {
+        (1.0 - self.age / self.lifetime).max(0.0)
+    }
+}
+
+/// All live particles. Particles are too many and too short-lived to be entities, so they are kept in a
+/// preallocated pool that is updated in place.
+pub struct Particles {
+    particles: Vec<Particle>,
+    rng: SmallRng,
+}
+
+impl Default for Particles {
+    fn default() -> Self {
+        Particles {
+            particles: Vec::with_capacity(MAX_PARTICLES),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+}
+
+impl Particles {
+    /// spawn `count` particles of `effect` at `pos`, emitted by something moving with `vel`
+    pub fn spawn(&mut self, effect: &ParticleEffect, pos: Cylindric, vel: Cylindric, count: usize) {
+        let count = count.min(MAX_PARTICLES - self.particles.len());
+        let r = pos.r.max(0.1);
+
+        for _ in 0..count {
+            // random direction, uniform on the sphere
+            let speed = effect.speed * self.rng.gen::<f32>();
+            let a = self.rng.gen_range(0.0, 2.0 * PI);
+            let c: f32 = self.rng.gen_range(-1.0, 1.0);
+            let s = (1.0 - c * c).sqrt();
+            let (dx, dy, dz) = (s * a.cos() * speed, s * a.sin() * speed, c * speed);
+
+            self.particles.push(Particle {
+                pos,
+                vel: Cylindric::new(
+                    vel.r * effect.inherit + effect.velocity.r + dx,
+                    // sideways speed is converted to revolutions per second at the emitter's radius
+                    vel.w * effect.inherit + effect.velocity.w + dy / (2.0 * PI * r),
+                    vel.z * effect.inherit + effect.velocity.z + dz,
+                ),
+                age: 0.0,
+                lifetime: effect.lifetime + effect.lifetime_spread * self.rng.gen::<f32>(),
+                size: effect.size,
+                color: effect.color,
+            });
+        }
+    }
+
+    /// move all particles and remove the dead ones
+    pub fn update(&mut self, dt: f32) {
+        let mut i = 0;
+        while i < self.particles.len() {
+            let alive = {
+                let p = &mut self.particles[i];
+                p.age += dt;
+
+                p.pos = (p.pos + p.vel * dt).wrapped();
+
+                p.age < p.lifetime
+            };
+
+            if alive {
+                i += 1;
+            } else {
+                // order does not matter, so fill the gap with the last particle
+                self.particles.swap_remove(i);
+            }
+        }
+    }
+
+    /// move all particles along the tunnel axis by `dz`
+    pub fn shift(&mut self, dz: f32) {
+        for p in &mut self.particles {
+            p.pos.z += dz;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Particle> {
+        self.particles.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.particles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.particles.is_empty()
+    }
+}
@@ -1,15 +1,135 @@
 use std::collections::{hash_map::Entry, HashMap};
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{Cursor, Read};
 use std::path;
+use std::time::{Duration, Instant, SystemTime};
 
 use ggez::{
-    graphics::Image, Context, GameResult,
+    graphics::{self, spritebatch::SpriteBatch, DrawParam, Image, Rect}, Context, GameError, GameResult,
 };
 
+use image;
+use toml;
+
+use atlas::{self, MAX_ATLAS_SIZE};
+use audio::{Sample, synth::SynthDef};
+
+/// Refers to a sprite image, which is a region of a texture.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ImageId(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TextureId(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct AnimationId(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SoundId(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SynthId(usize);
+
+/// Part of a texture that makes up one sprite image. Standalone images cover the whole texture,
+/// while images packed into an atlas share it with many others.
+#[derive(Debug, Copy, Clone)]
+pub struct Region {
+    pub texture: TextureId,
+    /// sub-rectangle in normalized texture coordinates
+    pub src: Rect,
+    /// size in pixels
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug)]
+pub struct Animation {
+    pub frames: Vec<ImageId>,
+    pub fps: f32,
+}
+
+/// Contents of the asset manifest. Maps asset names to files in the resource directory.
+#[derive(Debug, Default, Deserialize)]
+struct Manifest {
+    /// every image in the listed directories is packed into one texture atlas per entry
+    #[serde(default)]
+    atlases: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    images: HashMap<String, String>,
+    #[serde(default)]
+    animations: HashMap<String, AnimationDef>,
+    #[serde(default)]
+    sounds: HashMap<String, String>,
+    #[serde(default)]
+    synths: HashMap<String, SynthDef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnimationDef {
+    frames: Vec<String>,
+    fps: f32,
+}
+
+/// Development helper that polls the modification times of asset files.
+struct HotReload {
+    root: path::PathBuf,
+    last_poll: Instant,
+    mtimes: HashMap<OsString, SystemTime>,
+    data_files: Vec<OsString>,
+}
+
+impl HotReload {
+    const POLL_INTERVAL_MS: u64 = 500;
+
+    fn new<P: AsRef<path::Path>>(root: P) -> Self {
+        HotReload {
+            root: root.as_ref().to_owned(),
+            last_poll: Instant::now(),
+            mtimes: HashMap::new(),
+            data_files: Vec::new(),
+        }
+    }
+
+    /// return the files that were modified since the last poll
+    fn poll(&mut self, files: Vec<OsString>) -> Vec<OsString> {
+        let mut changed = Vec::new();
+        for file in files {
+            if let Some(t) = self.modified(&file) {
+                match self.mtimes.insert(file.clone(), t) {
+                    Some(prev) if prev != t => changed.push(file),
+                    _ => {}
+                }
+            }
+        }
+        changed
+    }
+
+    /// maps a path in ggez' virtual file system to the file in the resource directory
+    fn modified(&self, file: &OsStr) -> Option<SystemTime> {
+        let file = path::Path::new(file);
+        let file = file.strip_prefix("/").unwrap_or(file);
+        fs::metadata(self.root.join(file)).and_then(|m| m.modified()).ok()
+    }
+}
+
 #[derive(Default)]
 pub struct Resources {
-    handles: HashMap<OsString, usize>,
-    images: Vec<Image>,
+    manifest: Option<OsString>,
+    hot_reload: Option<HotReload>,
+
+    handles: HashMap<OsString, ImageId>,
+    regions: Vec<Region>,
+    textures: Vec<Image>,
+    batches: Vec<SpriteBatch>,
+    animations: Vec<Animation>,
+    sounds: Vec<Sample>,
+    synths: Vec<SynthDef>,
+
+    image_names: HashMap<String, ImageId>,
+    animation_names: HashMap<String, AnimationId>,
+    sound_names: HashMap<String, SoundId>,
+    synth_names: HashMap<String, SynthId>,
 }
 
 impl Resources {
@@ -17,23 +137,286 @@ impl Resources {
         Resources::default()
     }
 
+    /// Watch asset files in the resource directory of the source tree and reload them when they change.
+    /// Images are replaced in place, so handles to them stay valid.
+    pub fn enable_hot_reload(&mut self) {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("resources");
+        let mut hot_reload = HotReload::new(root);
+        hot_reload.poll(self.watched_files());
+        self.hot_reload = Some(hot_reload);
+    }
+
+    /// Add a data file to the set of watched files. Changes to data files are reported by
+    /// `reload_changed`, but it is up to the caller to load them again.
+    pub fn watch_data<P: AsRef<path::Path>>(&mut self, path: P) {
+        if let Some(ref mut hr) = self.hot_reload {
+            let file = path.as_ref().as_os_str().to_owned();
+            hr.data_files.push(file.clone());
+            hr.poll(vec![file]);
+        }
+    }
+
+    /// Reload images and the manifest if they were modified on disk. Returns the data files that
+    /// changed. Does nothing unless hot reloading is enabled.
+    pub fn reload_changed(&mut self, ctx: &mut Context) -> Vec<path::PathBuf> {
+        let files = match self.hot_reload {
+            Some(ref hr) if hr.last_poll.elapsed() >= Duration::from_millis(HotReload::POLL_INTERVAL_MS) => self.watched_files(),
+            _ => return Vec::new(),
+        };
+
+        let changed = {
+            let hr = self.hot_reload.as_mut().unwrap();
+            hr.last_poll = Instant::now();
+            hr.poll(files)
+        };
+
+        let mut changed_data = Vec::new();
+        for file in changed {
+            let result = if Some(&file) == self.manifest.as_ref() {
+                self.load_manifest(ctx, &file)
+            } else if let Some(&id) = self.handles.get(&file) {
+                self.reload_image(ctx, id, &file)
+            } else {
+                changed_data.push(path::PathBuf::from(file));
+                continue
+            };
+
+            match result {
+                Ok(()) => println!("reloaded {}", file.to_string_lossy()),
+                Err(e) => println!("failed to reload {}: {}", file.to_string_lossy(), e),
+            }
+        }
+        changed_data
+    }
+
+    fn watched_files(&self) -> Vec<OsString> {
+        let data = self.hot_reload.iter().flat_map(|hr| hr.data_files.iter());
+        self.manifest.iter()
+            .chain(self.handles.keys())
+            .chain(data)
+            .cloned()
+            .collect()
+    }
+
+    /// Images that live in an atlas are moved to a texture of their own, because the atlas cannot be updated
+    /// in place. That costs batching for this image, but hot reloading is for development only.
+    fn reload_image(&mut self, ctx: &mut Context, id: ImageId, path: &OsStr) -> GameResult<()> {
+        let image = Image::new(ctx, path)?;
+        let region = self.regions[id.0];
+        if region.src == Rect::one() {
+            self.batches[region.texture.0] = SpriteBatch::new(image.clone());
+            self.textures[region.texture.0] = image;
+            self.regions[id.0].width = self.textures[region.texture.0].width() as f32;
+            self.regions[id.0].height = self.textures[region.texture.0].height() as f32;
+        } else {
+            self.regions[id.0] = self.add_texture(image);
+        }
+        Ok(())
+    }
+
+    /// Load all assets listed in the manifest file at `path` and make them available by name.
+    /// Loading a manifest again updates existing animations and sounds in place.
+    pub fn load_manifest<P: AsRef<path::Path>>(&mut self, ctx: &mut Context, path: P) -> GameResult<()> {
+        let path = path.as_ref();
+        self.manifest = Some(path.as_os_str().to_owned());
+
+        let mut text = String::new();
+        ctx.filesystem.open(path)?.read_to_string(&mut text)?;
+
+        let manifest: Manifest = toml::from_str(&text)
+            .map_err(|e| GameError::ResourceLoadError(format!("{}: {}", path.display(), e)))?;
+
+        for (name, dirs) in manifest.atlases {
+            self.load_atlas(ctx, &dirs)
+                .map_err(|e| GameError::ResourceLoadError(format!("atlas '{}': {}", name, e)))?;
+        }
+
+        for (name, file) in manifest.images {
+            let id = self.add_image(ctx, &file)
+                .map_err(|e| GameError::ResourceLoadError(format!("image '{}' ({}): {}", name, file, e)))?;
+            self.image_names.insert(name, id);
+        }
+
+        for (name, def) in manifest.animations {
+            let mut frames = Vec::with_capacity(def.frames.len());
+            for file in &def.frames {
+                frames.push(self.add_image(ctx, file)
+                    .map_err(|e| GameError::ResourceLoadError(format!("animation '{}' ({}): {}", name, file, e)))?);
+            }
+            if frames.is_empty() {
+                return Err(GameError::ResourceLoadError(format!("animation '{}' has no frames", name)));
+            }
+            let animation = Animation { frames, fps: def.fps };
+            match self.animation_names.entry(name) {
+                Entry::Occupied(e) => self.animations[e.get().0] = animation,
+                Entry::Vacant(e) => {
+                    e.insert(AnimationId(self.animations.len()));
+                    self.animations.push(animation);
+                }
+            }
+        }
+
+        for (name, file) in manifest.sounds {
+            let sample = Resources::load_sample(ctx, &file)
+                .map_err(|e| GameError::ResourceLoadError(format!("sound '{}' ({}): {}", name, file, e)))?;
+            match self.sound_names.entry(name) {
+                Entry::Occupied(e) => self.sounds[e.get().0] = sample,
+                Entry::Vacant(e) => {
+                    e.insert(SoundId(self.sounds.len()));
+                    self.sounds.push(sample);
+                }
+            }
+        }
+
+        for (name, def) in manifest.synths {
+            match self.synth_names.entry(name) {
+                Entry::Occupied(e) => self.synths[e.get().0] = def,
+                Entry::Vacant(e) => {
+                    e.insert(SynthId(self.synths.len()));
+                    self.synths.push(def);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// decode a WAV, OGG or FLAC file
+    fn load_sample(ctx: &mut Context, path: &str) -> GameResult<Sample> {
+        let mut bytes = Vec::new();
+        ctx.filesystem.open(path)?.read_to_end(&mut bytes)?;
+        Sample::decode(Cursor::new(bytes))
+            .map_err(|e| GameError::ResourceLoadError(format!("{:?}", e)))
+    }
+
     pub fn add_image<P: AsRef<path::Path>>(
         &mut self,
         ctx: &mut Context,
         path: P,
-    ) -> GameResult<usize> {
+    ) -> GameResult<ImageId> {
         match self.handles.entry(path.as_ref().as_os_str().to_owned()) {
             Entry::Occupied(e) => Ok(*e.get()),
             Entry::Vacant(e) => {
-                let id = self.images.len();
-                self.images.push(Image::new(ctx, path)?);
+                let image = Image::new(ctx, path)?;
+                let region = Resources::add_texture_to(&mut self.textures, &mut self.batches, image);
+                let id = ImageId(self.regions.len());
+                self.regions.push(region);
                 e.insert(id);
                 Ok(id)
             }
         }
     }
 
-    pub fn get_image(&self, id: usize) -> &Image {
-        &self.images[id]
+    /// Pack all png files in the directories `dirs` into atlas textures. Afterwards, `add_image` with the path
+    /// of any of these files returns the image's region in the atlas. Files that were loaded before are skipped.
+    pub fn load_atlas<P: AsRef<path::Path>>(&mut self, ctx: &mut Context, dirs: &[P]) -> GameResult<()> {
+        let mut files = Vec::new();
+        for dir in dirs {
+            let dir = dir.as_ref();
+            for file in ctx.filesystem.read_dir(dir)? {
+                let file = if file.is_absolute() { file } else { dir.join(file) };
+                if file.extension().map_or(false, |ext| ext == "png") && !self.handles.contains_key(file.as_os_str()) {
+                    files.push(file);
+                }
+            }
+        }
+        files.sort();
+
+        let mut frames = Vec::with_capacity(files.len());
+        for file in files {
+            let mut bytes = Vec::new();
+            ctx.filesystem.open(&file)?.read_to_end(&mut bytes)?;
+            let img = image::load_from_memory(&bytes)
+                .map_err(|e| GameError::ResourceLoadError(format!("{}: {}", file.display(), e)))?;
+            frames.push((file.into_os_string(), img.to_rgba()));
+        }
+
+        for page in atlas::pack(frames, MAX_ATLAS_SIZE)? {
+            let (w, h) = page.image.dimensions();
+            let image = Image::from_rgba8(ctx, w as u16, h as u16, &page.image.into_raw())?;
+            let texture = Resources::add_texture_to(&mut self.textures, &mut self.batches, image).texture;
+
+            for (file, rect) in page.frames {
+                let id = ImageId(self.regions.len());
+                self.regions.push(Region {
+                    texture,
+                    src: Rect::new(rect.x / w as f32, rect.y / h as f32, rect.w / w as f32, rect.h / h as f32),
+                    width: rect.w,
+                    height: rect.h,
+                });
+                self.handles.insert(file, id);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_texture(&mut self, image: Image) -> Region {
+        Resources::add_texture_to(&mut self.textures, &mut self.batches, image)
+    }
+
+    /// returns a region that covers the whole new texture
+    fn add_texture_to(textures: &mut Vec<Image>, batches: &mut Vec<SpriteBatch>, image: Image) -> Region {
+        let region = Region {
+            texture: TextureId(textures.len()),
+            src: Rect::one(),
+            width: image.width() as f32,
+            height: image.height() as f32,
+        };
+        batches.push(SpriteBatch::new(image.clone()));
+        textures.push(image);
+        region
+    }
+
+    pub fn image(&self, name: &str) -> GameResult<ImageId> {
+        self.image_names.get(name).cloned()
+            .ok_or_else(|| GameError::ResourceLoadError(format!("unknown image '{}'", name)))
+    }
+
+    pub fn animation(&self, name: &str) -> GameResult<AnimationId> {
+        self.animation_names.get(name).cloned()
+            .ok_or_else(|| GameError::ResourceLoadError(format!("unknown animation '{}'", name)))
+    }
+
+    pub fn sound(&self, name: &str) -> GameResult<SoundId> {
+        self.sound_names.get(name).cloned()
+            .ok_or_else(|| GameError::ResourceLoadError(format!("unknown sound '{}'", name)))
+    }
+
+    pub fn synth(&self, name: &str) -> GameResult<SynthId> {
+        self.synth_names.get(name).cloned()
+            .ok_or_else(|| GameError::ResourceLoadError(format!("unknown synth '{}'", name)))
+    }
+
+    pub fn get_region(&self, id: ImageId) -> &Region {
+        &self.regions[id.0]
+    }
+
+    pub fn get_texture(&self, id: TextureId) -> &Image {
+        &self.textures[id.0]
+    }
+
+    pub fn get_animation(&self, id: AnimationId) -> &Animation {
+        &self.animations[id.0]
+    }
+
+    pub fn get_sound(&self, id: SoundId) -> &Sample {
+        &self.sounds[id.0]
+    }
+
+    pub fn get_synth(&self, id: SynthId) -> &SynthDef {
+        &self.synths[id.0]
+    }
+
+    /// queue a sprite for batched drawing with the texture `id`
+    pub fn add_to_batch(&mut self, id: TextureId, param: DrawParam) {
+        self.batches[id.0].add(param);
+    }
+
+    /// draw all sprites queued for texture `id` and empty the batch
+    pub fn flush_batch(&mut self, ctx: &mut Context, id: TextureId) -> GameResult<()> {
+        graphics::draw_ex(ctx, &self.batches[id.0], DrawParam::default())?;
+        self.batches[id.0].clear();
+        Ok(())
     }
 }
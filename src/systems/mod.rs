@@ -1,67 +1,259 @@
-use std::f32::consts::PI;
+use std::cmp::Ordering;
+use std::f32;
 use std::time::Duration;
 
-use ambisonic::sources::Noise;
-
 use ggez::{
-    graphics::{self, DrawParam, Drawable, Point2}, Context,
+    graphics::{self, Color, DrawMode, DrawParam, Drawable, MeshBuilder, Point2}, Context,
     timer::duration_to_f64,
 };
 
-use rodio::Source;
-
 use specs::prelude::*;
 
-use audio::Audio;
-use components::{Acc, Controlled, DeltaTime, Pos, Vel, RocketLauncher, RocketProjectile, SoundEmitter, Sprite, SpriteSize};
+use audio::{Audio, Bus, EnvironmentDef, Listener};
+use components::{Acc, Animated, Camera, Controlled, DeltaTime, Enemy, Energy, GravityField, Health, Lane, Launcher, LauncherState, Obstacle, ObstacleKind, ParticleEmitter, Pos, Projectile, ProjectilePhase, Vel, ShipHandling, SoundEmitter, Sprite, SpriteSize, TargetLock, Tunnel};
 use inputstate::{Input, InputState};
-use resources::Resources;
-use three_dee::{cylindric_pos_to_cartesian, cylindric_vel_to_cartesian, projection};
+use particles::{ParticleEffect, Particles};
+use resources::{ImageId, Region, Resources};
+use three_dee::{angle_difference, wrap_angle, Cylindric};
 use utils::fix_sprite;
+use weapons::{WeaponSound, Weapons};
 
 pub struct SpriteRenderSystem<'c> {
     ctx: &'c mut Context,
+    batched: bool,
+    depth: (f32, f32),
 }
 
 impl<'c> SpriteRenderSystem<'c> {
     pub fn new(ctx: &'c mut Context) -> Self {
-        SpriteRenderSystem { ctx }
+        SpriteRenderSystem { ctx, batched: true, depth: (f32::NEG_INFINITY, f32::INFINITY) }
+    }
+
+    /// draw each sprite with its own draw call instead of grouping them into sprite batches
+    pub fn unbatched(ctx: &'c mut Context) -> Self {
+        SpriteRenderSystem { ctx, batched: false, depth: (f32::NEG_INFINITY, f32::INFINITY) }
+    }
+
+    /// only draw sprites with `near <= z < far`, so other things can be drawn in between
+    pub fn in_depth_range(mut self, near: f32, far: f32) -> Self {
+        self.depth = (near, far);
+        self
     }
 }
 
 impl<'a, 'c> System<'a> for SpriteRenderSystem<'c> {
-    type SystemData = (Read<'a, Resources>, ReadStorage<'a, Pos>, ReadStorage<'a, Sprite>);
+    type SystemData = (Write<'a, Resources>, Read<'a, Camera>, ReadStorage<'a, Pos>, ReadStorage<'a, Sprite>);
+
+    fn run(&mut self, (mut res, camera, pos, spr): Self::SystemData) {
+        let mut sprites: Vec<_> = (&pos, &spr).join()
+            .filter(|(p, _)| p.0.z >= camera.z && p.0.z >= self.depth.0 && p.0.z < self.depth.1)
+            .map(|(p, s)| {
+                let region = res.get_region(s.0);
+                (p.0.z, region.texture, sprite_param(&camera, region, p, s))
+            })
+            .collect();
+
+        // draw from back to front
+        sprites.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+        if !self.batched {
+            for (_, id, param) in sprites {
+                res.get_texture(id).draw_ex(self.ctx, param).unwrap();
+            }
+            return
+        }
+
+        // consecutive sprites that share a texture go into the same batch; a batch is flushed
+        // as soon as a different texture comes up so that the depth order is preserved.
+        let mut current = None;
+        for (_, id, param) in sprites {
+            if current != Some(id) {
+                if let Some(prev) = current {
+                    res.flush_batch(self.ctx, prev).unwrap();
+                }
+                current = Some(id);
+            }
+            res.add_to_batch(id, param);
+        }
+
+        if let Some(prev) = current {
+            res.flush_batch(self.ctx, prev).unwrap();
+        }
+    }
+}
+
+fn sprite_param(camera: &Camera, region: &Region, p: &Pos, s: &Sprite) -> DrawParam {
+    let screen_pos = camera.project(p.0);
+
+    let scale = match s.1 {
+        SpriteSize::Auto{scale} => Point2::new(0.002, 0.002) * scale,
+        SpriteSize::Fixed{width, height} => Point2::new(width * 0.5 / region.width, height * 0.5 / region.height),
+    } * screen_pos.z;
+
+    fix_sprite(DrawParam {
+        src: region.src,
+        dest: screen_pos.into(),
+        rotation: camera.rotation(p.0),
+        offset: Point2::new(0.5, 0.5),
+        scale,
+        ..Default::default()
+    })
+}
+/// Draws all particles as tinted, fading dots. Particles are drawn on top of the sprites.
+pub struct ParticleRenderSystem<'c> {
+    ctx: &'c mut Context,
+    image: ImageId,
+}
+
+impl<'c> ParticleRenderSystem<'c> {
+    pub fn new(ctx: &'c mut Context, image: ImageId) -> Self {
+        ParticleRenderSystem { ctx, image }
+    }
+}
+
+impl<'a, 'c> System<'a> for ParticleRenderSystem<'c> {
+    type SystemData = (Write<'a, Resources>, Read<'a, Camera>, Read<'a, Particles>);
+
+    fn run(&mut self, (mut res, camera, particles): Self::SystemData) {
+        if particles.is_empty() {
+            return
+        }
+
+        let (texture, src, size) = {
+            let region = res.get_region(self.image);
+            (region.texture, region.src, region.width)
+        };
+
+        for p in particles.iter().filter(|p| p.pos.z >= camera.z) {
+            let screen_pos = camera.project(p.pos);
+            let remaining = p.remaining();
+            let color = Color::new(p.color.r, p.color.g, p.color.b, p.color.a * remaining);
+            let scale = p.size * (0.5 + 0.5 * remaining) * screen_pos.z / size;
 
-    fn run(&mut self, (res, pos, spr): Self::SystemData) {
-        for (p, s) in (&pos, &spr).join() {
-            if p.0.z < 0.0 {
+            res.add_to_batch(texture, fix_sprite(DrawParam {
+                src,
+                dest: screen_pos.into(),
+                offset: Point2::new(0.5, 0.5),
+                scale: Point2::new(scale, scale),
+                color: Some(color),
+                ..Default::default()
+            }));
+        }
+
+        res.flush_batch(self.ctx, texture).unwrap();
+    }
+}
+
+/// Draws walls and gates as solid bands along the tunnel wall and barriers as glowing arcs.
+pub struct ObstacleRenderSystem<'c> {
+    ctx: &'c mut Context,
+    depth: (f32, f32),
+}
+
+impl<'c> ObstacleRenderSystem<'c> {
+    pub fn new(ctx: &'c mut Context) -> Self {
+        ObstacleRenderSystem { ctx, depth: (f32::NEG_INFINITY, f32::INFINITY) }
+    }
+
+    /// only draw obstacles with `near <= z < far`, so they can be sorted in between the sprites
+    pub fn in_depth_range(mut self, near: f32, far: f32) -> Self {
+        self.depth = (near, far);
+        self
+    }
+}
+
+impl<'a, 'c> System<'a> for ObstacleRenderSystem<'c> {
+    type SystemData = (Read<'a, Camera>, Read<'a, Tunnel>, ReadStorage<'a, Pos>, ReadStorage<'a, Obstacle>);
+
+    fn run(&mut self, (camera, tunnel, pos, obstacles): Self::SystemData) {
+        // mesh colors are set per draw call, so solid obstacles and barriers go into separate meshes
+        let mut solid = MeshBuilder::new();
+        let mut barriers = MeshBuilder::new();
+        let (mut n_solid, mut n_barriers) = (0, 0);
+
+        for (p, o) in (&pos, &obstacles).join() {
+            if p.0.z < camera.z + 0.1 || p.0.z < self.depth.0 || p.0.z >= self.depth.1 {
                 continue
             }
 
-            let a = p.0.w * 2.0 * PI;
-            let screen_pos = projection(p.0);
+            let (start, length) = o.solid_span();
+            let steps = ((length * 96.0) as usize).max(2);
+            let arc = |r: f32| -> Vec<Point2> {
+                (0..steps + 1)
+                    .map(|i| camera.project(Cylindric::new(r, start + length * i as f32 / steps as f32, p.0.z)).into())
+                    .collect()
+            };
 
-            let img = res.get_image(s.0);
-
-            let scale = match s.1 {
-                SpriteSize::Auto{scale} => Point2::new(0.002, 0.002) * scale,
-                SpriteSize::Fixed{width, height} => Point2::new(width * 0.5 / img.width() as f32, height * 0.5 / img.height() as f32),
-            } * screen_pos.z;
-
-            img.draw_ex(
-                self.ctx,
-                fix_sprite(DrawParam {
-                    dest: screen_pos.into(),
-                    rotation: -a,
-                    offset: Point2::new(0.5, 0.5),
-                    scale,
-                    ..Default::default()
-                }),
-            )
-            .unwrap();
+            let outer = arc(tunnel.radius);
+            match o.kind {
+                ObstacleKind::Wall | ObstacleKind::Gate => {
+                    let mut inner = arc(tunnel.radius * (1.0 - o.height));
+                    inner.reverse();
+                    let band: Vec<_> = outer.into_iter().chain(inner).collect();
+                    solid.polygon(DrawMode::Fill, &band);
+                    n_solid += 1;
+                }
+                ObstacleKind::Barrier => {
+                    barriers.line(&outer, (0.05 * camera.scale(p.0.z)).max(0.01));
+                    n_barriers += 1;
+                }
+            }
         }
+
+        if n_solid > 0 {
+            graphics::set_color(self.ctx, Color::new(0.5, 0.5, 0.6, 1.0)).unwrap();
+            let mesh = solid.build(self.ctx).unwrap();
+            graphics::draw(self.ctx, &mesh, Point2::new(0.0, 0.0), 0.0).unwrap();
+        }
+        if n_barriers > 0 {
+            graphics::set_color(self.ctx, Color::new(0.3, 0.8, 1.0, 0.8)).unwrap();
+            let mesh = barriers.build(self.ctx).unwrap();
+            graphics::draw(self.ctx, &mesh, Point2::new(0.0, 0.0), 0.0).unwrap();
+        }
+        graphics::set_color(self.ctx, Color::new(1.0, 1.0, 1.0, 1.0)).unwrap();
+    }
+}
+
+/// half the size of the lock-on brackets, at a scale factor of 1
+const LOCK_ON_SIZE: f32 = 0.15;
+
+/// Draws brackets around the enemy that the player's homing weapons are locked onto.
+pub struct LockOnRenderSystem<'c> {
+    ctx: &'c mut Context,
+}
+
+impl<'c> LockOnRenderSystem<'c> {
+    pub fn new(ctx: &'c mut Context) -> Self {
+        LockOnRenderSystem { ctx }
     }
 }
+
+impl<'a, 'c> System<'a> for LockOnRenderSystem<'c> {
+    type SystemData = (Read<'a, Camera>, Read<'a, TargetLock>, ReadStorage<'a, Pos>);
+
+    fn run(&mut self, (camera, lock, pos): Self::SystemData) {
+        let target = match lock.0.and_then(|e| pos.get(e)) {
+            Some(p) if p.0.z >= camera.z => p.0,
+            _ => return,
+        };
+
+        let c = camera.project(target);
+        let half = LOCK_ON_SIZE * c.z;
+        let corner = half * 0.4;
+
+        let mut mb = MeshBuilder::new();
+        for &(sx, sy) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter() {
+            let (x, y) = (c.x + sx * half, c.y + sy * half);
+            mb.line(&[Point2::new(x, y - sy * corner), Point2::new(x, y), Point2::new(x - sx * corner, y)], (0.1 * half).max(0.005));
+        }
+
+        graphics::set_color(self.ctx, Color::new(1.0, 0.3, 0.2, 1.0)).unwrap();
+        let mesh = mb.build(self.ctx).unwrap();
+        graphics::draw(self.ctx, &mesh, Point2::new(0.0, 0.0), 0.0).unwrap();
+        graphics::set_color(self.ctx, Color::new(1.0, 1.0, 1.0, 1.0)).unwrap();
+    }
+}
+
 /*
 pub struct RectangleRenderSystem<'c> {
     ctx: &'c mut Context,
@@ -94,183 +286,580 @@ impl<'a, 'c> System<'a> for RectangleRenderSystem<'c> {
     }
 }
 */
-pub struct InputSystem;
+#[derive(Default)]
+pub struct InputSystem {
+    /// lane changes happen once per key press, so remember whether the keys were already down
+    lane_key_held: bool,
+    weapon_key_held: bool,
+}
 
 impl<'a> System<'a> for InputSystem {
-    type SystemData = (Read<'a, DeltaTime>, Read<'a, InputState>, ReadStorage<'a, Controlled>, WriteStorage<'a, RocketLauncher>, WriteStorage<'a, Pos>, WriteStorage<'a, Vel>);
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, InputState>, Read<'a, Tunnel>, Read<'a, Camera>, ReadStorage<'a, Controlled>, ReadStorage<'a, ShipHandling>, WriteStorage<'a, Lane>, WriteStorage<'a, Launcher>, WriteStorage<'a, Pos>, WriteStorage<'a, Vel>);
 
-    fn run(&mut self, (dt, inp, ctr, mut launcher, mut pos, mut vel): Self::SystemData) {
+    fn run(&mut self, (dt, inp, tunnel, camera, ctr, handling, mut lanes, mut launcher, mut pos, mut vel): Self::SystemData) {
         let dt = duration_to_f64(dt.0) as f32;
 
-        for (c, mut p, mut v) in (&ctr, &mut pos, &mut vel).join() {
-            p.0.w = p.0.w % 1.0;
-            if p.0.w < 0.0 {
-                p.0.w = 1.0 + p.0.w;
-            }
-
-            let mut delta = 0;
-
-            let target = match (inp.is_set(Input::Left), inp.is_set(Input::Right), inp.is_set(Input::Up), inp.is_set(Input::Down)) {
-                (true, false, false, false) => 0.75,
-                (false, true, false, false) => 0.25,
-                (false, false, true, false) => 0.5,
-                (false, false, false, true) => 0.0,
-                (true, false, true, false) => 0.625,
-                (true, false, false, true) => 0.875,
-                (false, true, true, false) => 0.375,
-                (false, true, false, true) => 0.125,
-                _ => {
-                    v.0.w = 0.0;
-                    continue
-                },
+        let lane_change = match (inp.is_set(Input::Inner), inp.is_set(Input::Outer)) {
+            _ if self.lane_key_held => 0,
+            (true, false) => 1,
+            (false, true) => -1,
+            _ => 0,
+        };
+        self.lane_key_held = inp.is_set(Input::Inner) || inp.is_set(Input::Outer);
+
+        for (_, h, lane, mut p, mut v) in (&ctr, &handling, &mut lanes, &mut pos, &mut vel).join() {
+            let last = tunnel.lanes.max(1) as i32 - 1;
+            lane.0 = (lane.0 as i32 + lane_change).max(0).min(last) as u32;
+            v.0.r = h.steer_radial(p.0.r, v.0.r, tunnel.lane_radius(lane.0), dt);
+
+            p.0 = p.0.wrapped();
+
+            // directions are on screen, and the camera rolls with the ship
+            let offset = match (inp.is_set(Input::Left), inp.is_set(Input::Right), inp.is_set(Input::Up), inp.is_set(Input::Down)) {
+                (true, false, false, false) => Some(0.75),
+                (false, true, false, false) => Some(0.25),
+                (false, false, true, false) => Some(0.5),
+                (false, false, false, true) => Some(0.0),
+                (true, false, true, false) => Some(0.625),
+                (true, false, false, true) => Some(0.875),
+                (false, true, true, false) => Some(0.375),
+                (false, true, false, true) => Some(0.125),
+                _ => None,
             };
+            let target = offset.map(|o| wrap_angle(camera.roll + o));
+
+            v.0.w = h.steer(p.0.w, v.0.w, target, dt);
+        }
 
-            let mut distance = target - p.0.w;
+        let next_weapon = inp.is_set(Input::NextWeapon) && !self.weapon_key_held;
+        self.weapon_key_held = inp.is_set(Input::NextWeapon);
 
-            if distance.abs() >= 0.5 {
-                distance = -distance;
+        for (_, l) in (&ctr, &mut launcher).join() {
+            if next_weapon {
+                l.select_next();
+            }
+            if let (LauncherState::Ready, true) = (l.state, inp.is_set(Input::Fire)) {
+                l.state = LauncherState::Fire;
             }
+        }
+    }
+}
+
+pub struct AnimationSystem;
+
+impl<'a> System<'a> for AnimationSystem {
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, Resources>, WriteStorage<'a, Animated>, WriteStorage<'a, Sprite>);
 
-            let mut direction = distance.min(0.01).max(-0.01);
+    fn run(&mut self, (dt, res, mut anims, mut sprites): Self::SystemData) {
+        for (anim, mut sprite) in (&mut anims, &mut sprites).join() {
+            anim.time += dt.0;
 
-            //p.0.w += direction;
-            v.0.w = direction / dt;
+            let animation = res.get_animation(anim.animation);
+            let frame = (duration_to_f64(anim.time) as f32 * animation.fps) as usize;
+            sprite.0 = animation.frames[frame % animation.frames.len()];
         }
+    }
+}
 
-        for (c, l) in (&ctr, &mut launcher).join() {
-            if inp.is_set(Input::Fire) {
-                match l {
-                    RocketLauncher::Ready => *l = RocketLauncher::Fire,
-                    RocketLauncher::Recharge(_) => {},
-                    RocketLauncher::Fire => {}
-                }
+/// homing weapons only lock onto enemies at most this far ahead
+const LOCK_RANGE: f32 = 30.0;
+
+/// Locks onto the nearest enemy ahead of the player while a homing weapon is selected. Enemies are compared
+/// by the distance around the tunnel plus the distance along it.
+pub struct TargetingSystem;
+
+impl<'a> System<'a> for TargetingSystem {
+    type SystemData = (Read<'a, Weapons>, Write<'a, TargetLock>, ReadStorage<'a, Controlled>, ReadStorage<'a, Launcher>, ReadStorage<'a, Enemy>, ReadStorage<'a, Pos>, Entities<'a>);
+
+    fn run(&mut self, (weapons, mut lock, ctr, launchers, enemies, pos, ents): Self::SystemData) {
+        lock.0 = None;
+
+        for (_, l, p) in (&ctr, &launchers, &pos).join() {
+            let homing = l.weapon().and_then(|id| weapons.get(id)).map_or(false, |w| w.def.homing > 0.0);
+            if !homing {
+                continue
             }
+
+            lock.0 = (&enemies, &pos, &*ents).join()
+                .filter(|&(_, q, _)| q.0.z > p.0.z && q.0.z - p.0.z < LOCK_RANGE)
+                .map(|(_, q, e)| (p.0.arc_distance(q.0, p.0.r) + q.0.z - p.0.z, e))
+                .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))
+                .map(|(_, e)| e);
         }
     }
 }
 
-pub struct RocketLauncherSystem;
+/// Fires the selected weapon of launchers that were told to, and lets them cool down afterwards.
+pub struct LauncherSystem;
 
-impl<'a> System<'a> for RocketLauncherSystem {
-    type SystemData = (Read<'a, DeltaTime>, Read<'a, Audio>, WriteStorage<'a, RocketLauncher>, ReadStorage<'a, Pos>, Entities<'a>, Read<'a, LazyUpdate>);
+impl<'a> System<'a> for LauncherSystem {
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, Audio>, Read<'a, Resources>, Read<'a, Tunnel>, Read<'a, Weapons>, Read<'a, TargetLock>, WriteStorage<'a, Launcher>, ReadStorage<'a, Pos>, Entities<'a>, Read<'a, LazyUpdate>);
 
-    fn run(&mut self, (dt, audio, mut launcher, pos, ents, updater): Self::SystemData) {
+    fn run(&mut self, (dt, audio, res, tunnel, weapons, lock, mut launcher, pos, ents, updater): Self::SystemData) {
         for (l, p) in (&mut launcher, &pos).join() {
-            *l = match l {
-                RocketLauncher::Ready => RocketLauncher::Ready,
-                RocketLauncher::Recharge(d) => {
-                    if *d == Duration::from_secs(0) {
-                        RocketLauncher::Ready
-                    } else if *d < dt.0 {
-                        RocketLauncher::Recharge(Duration::from_secs(0))
-                    } else {
-                        RocketLauncher::Recharge(*d - dt.0)
+            let weapon = l.weapon().and_then(|id| weapons.get(id).map(|w| (id, w)));
+            l.state = match (l.state, weapon) {
+                (LauncherState::Recharge(d), _) if d > dt.0 => LauncherState::Recharge(d - dt.0),
+                (LauncherState::Fire, Some((id, weapon))) => {
+                    let target = if weapon.def.homing > 0.0 { lock.0 } else { None };
+
+                    let n = weapon.def.projectiles.max(1);
+                    for i in 0..n {
+                        // fan the projectiles out evenly; a single one flies straight
+                        let side = if n > 1 { i as f32 / (n - 1) as f32 * 2.0 - 1.0 } else { 0.0 };
+
+                        let se = SoundEmitter::new(&audio, Bus::Sfx).with_fade_out(Duration::from_millis(150));
+                        if let (0, Some(sound)) = (i, weapon.launch_sound) {
+                            play_weapon_sound(&se, &res, sound, tunnel.pitch());
+                        }
+
+                        let mut builder = updater.create_entity(&ents)
+                            .with(*p)
+                            .with(Vel::new(0.0, side * weapon.def.spread, weapon.def.launch_speed))
+                            .with(Acc::new(0.0, 0.0, 0.0))
+                            .with(Sprite::new_auto(weapon.sprite, weapon.def.size))
+                            .with(Projectile { weapon: id, phase: ProjectilePhase::Launching(weapon.launch_time()), target })
+                            .with(se);
+                        if weapon.def.exhaust {
+                            builder = builder.with(ParticleEmitter::new(ParticleEffect::EXHAUST, 150.0).inactive());
+                        }
+                        builder.build();
                     }
-                }
-                RocketLauncher::Fire => {
-
-                    let se = SoundEmitter::new(&audio.ambisonic);
-                    se.mixer_controller.add(Noise::new(48000).amplify(0.1));
 
-                    let e = updater.create_entity(&ents)
-                        .with(*p)
-                        .with(Vel::new(0.0, 0.0, 0.5))
-                        .with(Acc::new(0.0, 0.0, 0.0))
-                        .with(Sprite::new_auto(3, 0.5))
-                        .with(RocketProjectile::Launching(25.0, Duration::from_millis(200)))
-                        .with(se)
-                        .build();
-
-                    RocketLauncher::Recharge(Duration::from_millis(350))
+                    LauncherState::Recharge(weapon.cooldown())
                 }
+                _ => LauncherState::Ready,
             };
         }
     }
 }
 
-pub struct RocketProjectileSystem;
+fn play_weapon_sound(se: &SoundEmitter, res: &Resources, sound: WeaponSound, pitch: f32) {
+    match sound {
+        WeaponSound::Sample(id) => se.play(res.get_sound(id), 0.5, pitch),
+        WeaponSound::Synth(id) => se.play_synth(res.get_synth(id)),
+    }
+}
+
+/// entities closer than this collide
+const HIT_DISTANCE: f32 = 0.3;
+
+/// Moves projectiles through the phases of their flight and lets them damage the enemies they hit.
+pub struct ProjectileSystem;
 
-impl<'a> System<'a> for RocketProjectileSystem {
-    type SystemData = (Read<'a, DeltaTime>, WriteStorage<'a, RocketProjectile>, WriteStorage<'a, Acc>, WriteStorage<'a, SoundEmitter>, Entities<'a>, Read<'a, LazyUpdate>);
+impl<'a> System<'a> for ProjectileSystem {
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, Audio>, Read<'a, Listener>, Read<'a, Resources>, Read<'a, Tunnel>, Read<'a, Weapons>, Write<'a, Particles>, ReadStorage<'a, Pos>, ReadStorage<'a, Vel>, ReadStorage<'a, Enemy>, WriteStorage<'a, Health>, WriteStorage<'a, Projectile>, WriteStorage<'a, Acc>, WriteStorage<'a, SoundEmitter>, WriteStorage<'a, ParticleEmitter>, Entities<'a>);
 
-    fn run(&mut self, (dt, mut rockets, mut accs, mut sounds, ents, updater): Self::SystemData) {
-        for (rocket, mut acc, mut se, ent) in (&mut rockets, &mut accs, &mut sounds, &*ents).join() {
-            *rocket = match *rocket {
-                RocketProjectile::Launching(a, mut d) => {
+    fn run(&mut self, (dt, audio, listener, res, tunnel, weapons, mut particles, pos, vel, enemies, mut health, mut projectiles, mut accs, mut sounds, mut emitters, ents): Self::SystemData) {
+        // enemies destroyed earlier in this update, which are only removed from the world later
+        let mut destroyed = Vec::new();
+
+        for (p, projectile, acc, se, ent) in (&pos, &mut projectiles, &mut accs, &mut sounds, &*ents).join() {
+            let weapon = match weapons.get(projectile.weapon) {
+                Some(weapon) => weapon,
+                // the weapon was removed while the projectile was in flight
+                None => {
+                    ents.delete(ent);
+                    continue
+                }
+            };
+
+            let hit = (&pos, &enemies, &*ents).join()
+                .find(|&(q, _, e)| !destroyed.contains(&e) && p.0.distance(q.0) < HIT_DISTANCE)
+                .map(|(q, _, e)| (q.0, e));
+
+            let explode = match hit {
+                Some((q, e)) => {
+                    // enemies without health are destroyed by any hit
+                    let killed = health.get_mut(e).map_or(true, |h| {
+                        h.current -= weapon.def.damage;
+                        h.current <= 0.0
+                    });
+                    if killed {
+                        let v = vel.get(e).map_or(Cylindric::new(0.0, 0.0, 0.0), |v| v.0);
+                        particles.spawn(&ParticleEffect::DEBRIS, q, v, 60);
+                        ents.delete(e);
+                        destroyed.push(e);
+                    }
+                    true
+                }
+                None => false,
+            };
+
+            projectile.phase = match projectile.phase {
+                _ if explode => ProjectilePhase::Flying(Duration::from_secs(0)),
+                ProjectilePhase::Launching(d) => {
                     if d > dt.0 {
-                        RocketProjectile::Launching(a, d - dt.0)
+                        ProjectilePhase::Launching(d - dt.0)
                     } else {
-                        acc.0.z = a;
-                        se.mixer_controller.add(Noise::new(48000));
-                        RocketProjectile::Accelerating(Duration::from_millis(200))
+                        acc.0.z = weapon.def.acceleration;
+                        if let Some(sound) = weapon.engine_sound {
+                            play_weapon_sound(se, &res, sound, tunnel.pitch());
+                        }
+                        if let Some(emitter) = emitters.get_mut(ent) {
+                            emitter.active = true;
+                        }
+                        ProjectilePhase::Accelerating(weapon.burn_time())
                     }
                 }
-                RocketProjectile::Accelerating(mut d) => {
+                ProjectilePhase::Accelerating(d) => {
                     if d > dt.0 {
-                        RocketProjectile::Accelerating(d - dt.0)
+                        ProjectilePhase::Accelerating(d - dt.0)
                     } else {
                         acc.0.z = 0.0;
-                        RocketProjectile::Flying(Duration::from_millis(600))
+                        if let Some(emitter) = emitters.get_mut(ent) {
+                            emitter.active = false;
+                        }
+                        ProjectilePhase::Flying(weapon.flight_time())
                     }
                 }
-                RocketProjectile::Flying(mut d) => {
-                    if d > dt.0 {
-                        RocketProjectile::Flying(d - dt.0)
-                    } else {
-                        se.spatial_controller.stop();
-                        ents.delete(ent);
-                        RocketProjectile::Flying(d)
+                ProjectilePhase::Flying(d) => ProjectilePhase::Flying(d),
+            };
+
+            if let ProjectilePhase::Flying(d) = projectile.phase {
+                if d > dt.0 {
+                    projectile.phase = ProjectilePhase::Flying(d - dt.0);
+                } else {
+                    let v = vel.get(ent).map_or(Cylindric::new(0.0, 0.0, 0.0), |v| v.0);
+                    particles.spawn(&ParticleEffect::EXPLOSION, p.0, v, 40);
+                    if let Some(sound) = weapon.explosion_sound {
+                        let rel = listener.relative_position(p.0);
+                        audio.play_at(res.get_sound(sound), rel, 0.8 * listener.attenuation(rel.length()), tunnel.pitch());
                     }
+                    ents.delete(ent);
+                }
+            }
+        }
+    }
+}
+
+/// Steers homing projectiles towards their target once their engine has ignited, by aiming for where the
+/// target will be when the projectile reaches its depth.
+pub struct HomingSystem;
+
+impl<'a> System<'a> for HomingSystem {
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, Weapons>, Read<'a, Tunnel>, WriteStorage<'a, Projectile>, ReadStorage<'a, Pos>, WriteStorage<'a, Vel>, Entities<'a>);
+
+    fn run(&mut self, (dt, weapons, tunnel, mut projectiles, pos, mut vel, ents): Self::SystemData) {
+        let dt = duration_to_f64(dt.0) as f32;
+
+        // new radial and angular speeds, or `None` for projectiles that have lost their target
+        let steering: Vec<_> = (&projectiles, &pos, &vel, &*ents).join()
+            .filter(|&(pr, _, _, _)| match pr.phase {
+                ProjectilePhase::Launching(_) => false,
+                _ => pr.target.is_some() && weapons.get(pr.weapon).map_or(false, |w| w.def.homing > 0.0),
+            })
+            .map(|(pr, p, v, e)| {
+                let q = match pr.target.and_then(|t| pos.get(t)) {
+                    // once past the target there is nothing left to aim for
+                    Some(q) if q.0.z > p.0.z => q.0,
+                    _ => return (e, None),
+                };
+                let u = pr.target.and_then(|t| vel.get(t)).map_or(Cylindric::new(0.0, 0.0, 0.0), |u| u.0);
+
+                // time until the projectile reaches the target's depth
+                let t = ((q.z - p.0.z) / (v.0.z - u.z).max(0.1)).max(0.05);
+                let w = angle_difference(p.0.w, q.w) / t + u.w;
+                let r = (q.r - p.0.r) / t + u.r;
+
+                let k = (weapons.get(pr.weapon).map_or(0.0, |w| w.def.homing) * dt).min(1.0);
+                let r = v.0.r + (r - v.0.r) * k;
+                // never steer through the tunnel wall
+                let r = if dt > 0.0 { r.min((tunnel.radius - p.0.r) / dt) } else { r };
+                (e, Some((v.0.w + (w - v.0.w) * k, r)))
+            })
+            .collect();
+
+        for (e, steer) in steering {
+            match steer {
+                Some((w, r)) => {
+                    if let Some(v) = vel.get_mut(e) {
+                        v.0.w = w;
+                        v.0.r = r;
+                    }
+                }
+                None => {
+                    if let Some(pr) = projectiles.get_mut(e) {
+                        pr.target = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Enemies touching the player's ship damage it.
+#[derive(Default)]
+pub struct ShipCollisionSystem {
+    /// enemies that touched the ship in the previous update
+    touching: Vec<Entity>,
+}
+
+impl ShipCollisionSystem {
+    /// health lost per second of contact
+    const DAMAGE_RATE: f32 = 40.0;
+    /// screen shake when an enemy starts touching the ship
+    const HIT_SHAKE: f32 = 0.1;
+}
+
+impl<'a> System<'a> for ShipCollisionSystem {
+    type SystemData = (Read<'a, DeltaTime>, Write<'a, Camera>, Write<'a, Particles>, ReadStorage<'a, Controlled>, ReadStorage<'a, Enemy>, ReadStorage<'a, Pos>, ReadStorage<'a, Vel>, WriteStorage<'a, Health>, Entities<'a>);
+
+    fn run(&mut self, (dt, mut camera, mut particles, ctr, enemies, pos, vel, mut health, ents): Self::SystemData) {
+        let dt = duration_to_f64(dt.0) as f32;
+        let mut touching = Vec::new();
+
+        for (_, p, v, h) in (&ctr, &pos, &vel, &mut health).join() {
+            let contacts: Vec<_> = (&enemies, &pos, &*ents).join()
+                .filter(|(_, q, _)| p.0.distance(q.0) < HIT_DISTANCE)
+                .map(|(_, _, e)| e)
+                .collect();
+
+            if !contacts.is_empty() {
+                h.current -= Self::DAMAGE_RATE * contacts.len() as f32 * dt;
+                particles.spawn(&ParticleEffect::SPARKS, p.0, v.0, 4 * contacts.len());
+            }
+
+            // the shake would decay as fast as a steady contact adds it, so each new hit gives one jolt
+            for e in &contacts {
+                if !self.touching.contains(e) {
+                    camera.add_shake(Self::HIT_SHAKE);
                 }
             }
+            touching.extend(contacts);
         }
+
+        self.touching = touching;
     }
 }
 
+/// Lets the black hole act on everything that moves: space is dragged around the axis and things fall towards
+/// the axis.
+pub struct GravitySystem;
+
+impl<'a> System<'a> for GravitySystem {
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, GravityField>, WriteStorage<'a, Pos>, WriteStorage<'a, Vel>);
+
+    fn run(&mut self, (dt, gravity, mut pos, mut vel): Self::SystemData) {
+        let dt = duration_to_f64(dt.0) as f32;
+
+        for (p, v) in (&mut pos, &mut vel).join() {
+            let dt = dt * gravity.dilation(p.0.z);
+
+            // frame dragging moves things without accelerating them
+            p.0.w += gravity.drag_velocity(p.0.z) * dt;
+            v.0.r += gravity.radial_acceleration(p.0.z) * dt;
+
+            // nothing can fall past the axis
+            if p.0.r <= 0.0 && v.0.r < 0.0 {
+                p.0.r = 0.0;
+                v.0.r = 0.0;
+            }
+        }
+    }
+}
+
+/// Moves everything by its velocity. Time dilation slows down things the closer they are to the horizon.
 pub struct KinematicSystem;
 
 impl<'a> System<'a> for KinematicSystem {
-    type SystemData = (Read<'a, DeltaTime>, ReadStorage<'a, Acc>, WriteStorage<'a, Vel>, WriteStorage<'a, Pos>);
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, GravityField>, ReadStorage<'a, Acc>, WriteStorage<'a, Vel>, WriteStorage<'a, Pos>);
 
-    fn run(&mut self, (dt, acc, mut vel, mut pos): Self::SystemData) {
+    fn run(&mut self, (dt, gravity, acc, mut vel, mut pos): Self::SystemData) {
         let dt = duration_to_f64(dt.0) as f32;
 
-        for (a, mut v) in (&acc, &mut vel).join() {
-            v.0.r += a.0.r * dt;
-            v.0.w += a.0.w * dt;
-            v.0.z += a.0.z * dt;
+        for (a, mut v, p) in (&acc, &mut vel, &pos).join() {
+            v.0 += a.0 * dt * gravity.dilation(p.0.z);
         }
 
         for (v, mut p) in (&vel, &mut pos).join() {
-            p.0.r += v.0.r * dt;
-            p.0.w += v.0.w * dt;
-            p.0.z += v.0.z * dt;
+            let dt = dt * gravity.dilation(p.0.z);
+            p.0 = (p.0 + v.0 * dt).wrapped();
+        }
+    }
+}
+
+/// Lets the player boost or brake. Everything in the world moves at cruising speed relative to the ship, so
+/// flying faster or slower moves it towards or away from the ship by the difference.
+pub struct ThrottleSystem;
+
+impl<'a> System<'a> for ThrottleSystem {
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, InputState>, Write<'a, Tunnel>, Write<'a, Particles>, ReadStorage<'a, Controlled>, ReadStorage<'a, Obstacle>, WriteStorage<'a, Energy>, WriteStorage<'a, Pos>);
+
+    fn run(&mut self, (dt, input, mut tunnel, mut particles, ctr, obstacles, mut energy, mut pos): Self::SystemData) {
+        let dt = duration_to_f64(dt.0) as f32;
+
+        let held = input.is_set(Input::Boost);
+        let mut boost = held;
+        for (_, e) in (&ctr, &mut energy).join() {
+            if !held {
+                e.depleted = false;
+            }
+            boost = held && !e.depleted;
+            if boost {
+                e.current = (e.current - Energy::DRAIN * dt).max(0.0);
+                e.depleted = e.current == 0.0;
+            } else {
+                e.current = (e.current + Energy::RECHARGE * dt).min(e.max);
+            }
+        }
+
+        let target = if boost {
+            tunnel.boost_speed
+        } else if input.is_set(Input::Brake) {
+            tunnel.brake_speed
+        } else {
+            tunnel.speed
+        };
+        let step = tunnel.acceleration * dt;
+        tunnel.current_speed += (target - tunnel.current_speed).max(-step).min(step);
+
+        // obstacles are fixed to the tunnel and placed by the camera instead
+        let dz = -tunnel.relative_speed() * dt;
+        for (p, _, _) in (&mut pos, !&ctr, !&obstacles).join() {
+            p.0.z += dz;
+        }
+        particles.shift(dz);
+    }
+}
+
+/// Spawns particles from active emitters and moves all particles.
+pub struct ParticleSystem;
+
+impl<'a> System<'a> for ParticleSystem {
+    type SystemData = (Read<'a, DeltaTime>, Write<'a, Particles>, ReadStorage<'a, Pos>, ReadStorage<'a, Vel>, WriteStorage<'a, ParticleEmitter>);
+
+    fn run(&mut self, (dt, mut particles, pos, vel, mut emitters): Self::SystemData) {
+        let dt = duration_to_f64(dt.0) as f32;
+
+        particles.update(dt);
+
+        for (p, v, e) in (&pos, vel.maybe(), &mut emitters).join() {
+            if !e.active {
+                e.pending = 0.0;
+                continue
+            }
+
+            e.pending += e.rate * dt;
+            let n = e.pending as usize;
+            e.pending -= n as f32;
+
+            let v = v.map_or(Cylindric::new(0.0, 0.0, 0.0), |v| v.0);
+            particles.spawn(&e.effect, p.0, v, n);
+        }
+    }
+}
+
+/// Keeps obstacles in their place in the tunnel as the camera flies on, and damages the player's ship when it
+/// flies into one.
+pub struct ObstacleSystem;
+
+impl<'a> System<'a> for ObstacleSystem {
+    type SystemData = (Write<'a, Camera>, Read<'a, Tunnel>, Write<'a, Particles>, ReadStorage<'a, Controlled>, WriteStorage<'a, Pos>, ReadStorage<'a, Vel>, WriteStorage<'a, Health>, WriteStorage<'a, Obstacle>, Entities<'a>);
+
+    fn run(&mut self, (mut camera, tunnel, mut particles, ctr, mut pos, vel, mut health, mut obstacles, ents): Self::SystemData) {
+        for (p, o, e) in (&mut pos, &obstacles, &*ents).join() {
+            p.0.z = o.at - camera.distance + camera.z;
+            // far enough behind the camera to be gone
+            if p.0.z < camera.z - 1.0 {
+                ents.delete(e);
+            }
+        }
 
-            p.0.w = p.0.w % 1.0;
-            if p.0.w < 0.0 {
-                p.0.w = 1.0 + p.0.w;
+        let ship = (&ctr, &pos, &vel).join().next().map(|(_, p, v)| (p.0, v.0));
+        let (ship_pos, ship_vel) = match ship {
+            Some(ship) => ship,
+            None => return,
+        };
+
+        for (p, o) in (&pos, &mut obstacles).join() {
+            if o.passed || p.0.z > ship_pos.z {
+                continue
+            }
+            o.passed = true;
+
+            if o.blocks(ship_pos.w, ship_pos.r, tunnel.radius) {
+                for (_, h) in (&ctr, &mut health).join() {
+                    h.current -= o.damage();
+                }
+                particles.spawn(&ParticleEffect::SPARKS, ship_pos, ship_vel, 40);
+                camera.add_shake(0.1);
             }
         }
     }
 }
 
+/// Flies the camera through the tunnel and keeps the player's ship at the bottom of the screen.
+pub struct CameraSystem;
+
+impl<'a> System<'a> for CameraSystem {
+    type SystemData = (Read<'a, DeltaTime>, Read<'a, Tunnel>, Write<'a, Camera>, ReadStorage<'a, Controlled>, ReadStorage<'a, Pos>);
+
+    fn run(&mut self, (dt, tunnel, mut camera, ctr, pos): Self::SystemData) {
+        let target = (&ctr, &pos).join().next().map(|(_, p)| p.0.w);
+        camera.update(duration_to_f64(dt.0) as f32, tunnel.current_speed, target);
+    }
+}
+
 pub struct SpatialAudioSystem;
 
 
 impl<'a> System<'a> for SpatialAudioSystem {
-    type SystemData = (ReadStorage<'a, Vel>, ReadStorage<'a, Pos>, WriteStorage<'a, SoundEmitter>);
+    type SystemData = (Write<'a, Listener>, Read<'a, Tunnel>, ReadStorage<'a, Controlled>, ReadStorage<'a, Vel>, ReadStorage<'a, Pos>, WriteStorage<'a, SoundEmitter>);
+
+    fn run(&mut self, (mut listener, tunnel, ctr, vel, pos, mut emitter): Self::SystemData) {
+        // the listener rides along with the player's ship
+        if let Some((_, p)) = (&ctr, &pos).join().next() {
+            listener.pos = p.0;
+        }
+        if let Some((_, v)) = (&ctr, &vel).join().next() {
+            // boosting flies into the sounds ahead, which raises their pitch through the doppler effect
+            listener.vel = v.0 + Cylindric::new(0.0, 0.0, tunnel.relative_speed());
+        }
 
-    fn run(&mut self, (vel, pos, mut emitter): Self::SystemData) {
         for (p, e) in (&pos, &mut emitter).join() {
-            let tmp = cylindric_pos_to_cartesian(p.0);
+            let tmp = listener.relative_position(p.0);
             // in Ambisonic z points up, but our z points into the screen
             e.spatial_controller.adjust_position([tmp.x, tmp.z, tmp.y]);
+            e.set_attenuation(listener.attenuation(tmp.length()));
         }
 
         for (v, p, e) in (&vel, &pos, &mut emitter).join() {
-            let tmp = cylindric_vel_to_cartesian(v.0, p.0);
+            let tmp = listener.relative_velocity(v.0, p.0);
             // in Ambisonic z points up, but our z points into the screen
             e.spatial_controller.set_velocity([tmp.x, tmp.z, tmp.y])
         }
     }
-}
\ No newline at end of file
+}
+
+/// Silences the least audible sound emitters when more than `Audio::max_voices` are playing.
+pub struct VoiceLimitSystem;
+
+impl<'a> System<'a> for VoiceLimitSystem {
+    type SystemData = (Read<'a, Audio>, ReadStorage<'a, SoundEmitter>);
+
+    fn run(&mut self, (audio, emitter): Self::SystemData) {
+        let mut voices: Vec<_> = emitter.join()
+            .map(|e| (e.loudness * e.attenuation() * audio.buses.gain(e.bus), e))
+            .collect();
+
+        // loudest first
+        voices.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+        for (i, (_, e)) in voices.into_iter().enumerate() {
+            e.set_culled(i >= audio.max_voices);
+        }
+    }
+}
+
+/// Adapts the reverb to the tunnel the player is currently flying through.
+pub struct EnvironmentSystem;
+
+impl<'a> System<'a> for EnvironmentSystem {
+    type SystemData = (Read<'a, Audio>, Read<'a, EnvironmentDef>, Read<'a, Tunnel>);
+
+    fn run(&mut self, (audio, def, tunnel): Self::SystemData) {
+        let (wet, feedback, delay, damping) = def.params(tunnel.radius, tunnel.current_speed);
+        audio.environment.set(wet, feedback, delay, damping);
+    }
+}
@@ -1,19 +1,205 @@
 use std::f32::consts::PI;
+use std::fmt::Debug;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
 
 use ggez::graphics::Point2;
 
-pub fn projection(pos: Cylindric) -> Cartesian {
-    let a = pos.w * 2.0 * PI;
-    let f = projection_factor(pos.z);
-    let r = pos.r * f;
-    Cartesian::new(a.sin() * r, a.cos() * r, f)
+/// Maps positions in the tunnel to the screen. The tunnel axis is at the origin and `z` is the depth in front of
+/// the camera.
+pub trait Projection: Debug + Send + Sync {
+    /// distance from the tunnel axis on screen of a point at radius `r` and depth `z`
+    fn radius(&self, r: f32, z: f32) -> f32;
+
+    /// on-screen size of a unit-sized object at depth `z`
+    fn factor(&self, z: f32) -> f32;
+
+    /// on-screen size of a unit-sized object at radius `r` and depth `z`. Only differs from `factor` if the
+    /// projection distorts the tunnel cross-section.
+    fn scale(&self, _r: f32, z: f32) -> f32 {
+        self.factor(z)
+    }
+
+    /// inverse of `radius`: the radius at depth `z` that appears `screen_r` away from the axis
+    fn inverse_radius(&self, screen_r: f32, z: f32) -> Option<f32>;
+
+    /// inverse of `radius`: the depth at which radius `r` appears `screen_r` away from the axis
+    fn inverse_depth(&self, screen_r: f32, r: f32) -> Option<f32>;
+
+    /// screen position of `pos`; the `z` of the result is the size factor at that position
+    fn project(&self, pos: Cylindric) -> Cartesian {
+        let a = pos.w * 2.0 * PI;
+        let r = self.radius(pos.r, pos.z);
+        Cartesian::new(a.sin() * r, a.cos() * r, self.scale(pos.r, pos.z))
+    }
+
+    /// the point at depth `z` that is projected to `screen`
+    fn unproject_at_depth(&self, screen: Point2, z: f32) -> Option<Cylindric> {
+        let (w, screen_r) = screen_polar(screen);
+        self.inverse_radius(screen_r, z).map(|r| Cylindric::new(r, w, z))
+    }
+
+    /// the point on the cylinder of radius `r` that is projected to `screen`
+    fn unproject_at_radius(&self, screen: Point2, r: f32) -> Option<Cylindric> {
+        let (w, screen_r) = screen_polar(screen);
+        self.inverse_depth(screen_r, r).map(|z| Cylindric::new(r, w, z))
+    }
+}
+
+/// angle (in revolutions, as `Cylindric::w`) and distance from the axis of a screen point
+fn screen_polar(screen: Point2) -> (f32, f32) {
+    // `project` puts angle 0 at the bottom of the screen, and y points down
+    let w = screen.x.atan2(screen.y) / (2.0 * PI);
+    let w = if w < 0.0 { w + 1.0 } else { w };
+    (w, (screen.x * screen.x + screen.y * screen.y).sqrt())
+}
+
+/// The classic look of the game: a perspective with the eye very close to the screen, so the tunnel
+/// opens up quickly in front of the player.
+#[derive(Debug, Copy, Clone)]
+pub struct Hyperbolic;
+
+impl Projection for Hyperbolic {
+    fn radius(&self, r: f32, z: f32) -> f32 {
+        r * self.factor(z)
+    }
+
+    fn factor(&self, z: f32) -> f32 {
+        10.0 / (1.0 + z * 3.0)
+    }
+
+    fn inverse_radius(&self, screen_r: f32, z: f32) -> Option<f32> {
+        if z > -1.0 / 3.0 { Some(screen_r / self.factor(z)) } else { None }
+    }
+
+    fn inverse_depth(&self, screen_r: f32, r: f32) -> Option<f32> {
+        if screen_r > 0.0 { Some((10.0 * r / screen_r - 1.0) / 3.0) } else { None }
+    }
+}
+
+/// A pinhole camera placed `eye` units behind the screen. The field of view is applied by the camera.
+#[derive(Debug, Copy, Clone)]
+pub struct Perspective {
+    pub eye: f32,
+}
+
+impl Perspective {
+    /// focal length for a 90° field of view across the screen, which is 4 units wide
+    const FOCAL_LENGTH: f32 = 2.0;
+}
+
+impl Default for Perspective {
+    fn default() -> Self {
+        Perspective { eye: 1.0 }
+    }
+}
+
+impl Projection for Perspective {
+    fn radius(&self, r: f32, z: f32) -> f32 {
+        r * self.factor(z)
+    }
+
+    fn factor(&self, z: f32) -> f32 {
+        Perspective::FOCAL_LENGTH / (z + self.eye).max(1e-3)
+    }
+
+    fn inverse_radius(&self, screen_r: f32, z: f32) -> Option<f32> {
+        if z + self.eye > 0.0 { Some(screen_r / self.factor(z)) } else { None }
+    }
+
+    fn inverse_depth(&self, screen_r: f32, r: f32) -> Option<f32> {
+        if screen_r > 0.0 { Some(r * Perspective::FOCAL_LENGTH / screen_r - self.eye) } else { None }
+    }
+}
+
+/// Gravitational lensing around a black hole at the far end of the tunnel: the centre of the screen is
+/// pushed outwards onto the Einstein ring, and distant parts of the tunnel wrap around it.
+#[derive(Debug, Copy, Clone)]
+pub struct Fisheye {
+    /// screen radius of the Einstein ring
+    pub einstein_radius: f32,
+}
+
+impl Default for Fisheye {
+    fn default() -> Self {
+        Fisheye { einstein_radius: 0.3 }
+    }
+}
+
+impl Projection for Fisheye {
+    fn radius(&self, r: f32, z: f32) -> f32 {
+        // image position of a point lens
+        let rho = Hyperbolic.radius(r, z);
+        let e = self.einstein_radius;
+        (rho + (rho * rho + 4.0 * e * e).sqrt()) * 0.5
+    }
+
+    fn factor(&self, z: f32) -> f32 {
+        // lensing magnifies things close to the ring; use the magnification at the usual radius of the ships
+        self.radius(1.0, z)
+    }
+
+    fn scale(&self, r: f32, z: f32) -> f32 {
+        // geometric mean of the stretch along the ring and across it
+        let r = r.max(0.05);
+        let tangential = self.radius(r, z) / r;
+        let radial = (self.radius(r + 0.01, z) - self.radius(r - 0.01, z)) / 0.02;
+        (tangential * radial).sqrt()
+    }
+
+    fn inverse_radius(&self, screen_r: f32, z: f32) -> Option<f32> {
+        self.unlens(screen_r).and_then(|rho| Hyperbolic.inverse_radius(rho, z))
+    }
+
+    fn inverse_depth(&self, screen_r: f32, r: f32) -> Option<f32> {
+        self.unlens(screen_r).and_then(|rho| Hyperbolic.inverse_depth(rho, r))
+    }
+}
+
+impl Fisheye {
+    /// where an image at `screen_r` would be without the lens; nothing is seen inside the Einstein ring
+    fn unlens(&self, screen_r: f32) -> Option<f32> {
+        let e = self.einstein_radius;
+        if screen_r >= e { Some(screen_r - e * e / screen_r) } else { None }
+    }
 }
 
-pub fn projection_factor(z: f32) -> f32 {
-    //10.0 / (9.0 + z + 0.1 * (z.abs() * z - 1.0))
-    //10.0 / (9.0 + z.abs() * z / 5.0)
-    //10.0 / (z + 1.0 * (z.abs() * z))
-    10.0 / (1.0 + z * 3.0)
+/// The projection models the player can choose from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProjectionKind {
+    Hyperbolic,
+    Perspective,
+    Fisheye,
+}
+
+impl ProjectionKind {
+    pub const ALL: [ProjectionKind; 3] = [ProjectionKind::Hyperbolic, ProjectionKind::Perspective, ProjectionKind::Fisheye];
+
+    pub fn create(&self) -> Box<Projection> {
+        match self {
+            ProjectionKind::Hyperbolic => Box::new(Hyperbolic),
+            ProjectionKind::Perspective => Box::new(Perspective::default()),
+            ProjectionKind::Fisheye => Box::new(Fisheye::default()),
+        }
+    }
+
+    /// the next kind, wrapping around after the last one
+    pub fn next(&self) -> ProjectionKind {
+        let i = ProjectionKind::ALL.iter().position(|k| k == self).unwrap_or(0);
+        ProjectionKind::ALL[(i + 1) % ProjectionKind::ALL.len()]
+    }
+}
+
+/// wrap an angle given in revolutions into `[0, 1)`
+pub fn wrap_angle(w: f32) -> f32 {
+    let w = w % 1.0;
+    let w = if w < 0.0 { w + 1.0 } else { w };
+    // tiny negative angles round up to 1.0 when shifted
+    if w >= 1.0 { 0.0 } else { w }
+}
+
+/// signed difference `to - from` between two angles in revolutions, the shorter way around: in `[-0.5, 0.5)`
+pub fn angle_difference(from: f32, to: f32) -> f32 {
+    wrap_angle(to - from + 0.5) - 0.5
 }
 
 pub fn cylindric_pos_to_cartesian(pos: Cylindric) -> Cartesian {
@@ -39,6 +225,20 @@ impl Cartesian {
     pub fn new(x: f32, y: f32, z: f32) -> Self {
         Cartesian { x, y, z }
     }
+
+    pub fn length(&self) -> f32 {
+        self.dot(*self).sqrt()
+    }
+
+    pub fn dot(&self, other: Cartesian) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+}
+
+impl From<Cylindric> for Cartesian {
+    fn from(pos: Cylindric) -> Self {
+        cylindric_pos_to_cartesian(pos)
+    }
 }
 
 impl From<Cartesian> for Point2 {
@@ -58,10 +258,185 @@ impl Cylindric {
     pub fn new(r: f32, w: f32, z: f32) -> Self {
         Cylindric { r, w, z }
     }
+
+    /// the same position with `w` in `[0, 1)`
+    pub fn wrapped(self) -> Self {
+        Cylindric::new(self.r, wrap_angle(self.w), self.z)
+    }
+
+    /// angle from `self` to `other`, the shorter way around the tunnel
+    pub fn angle_to(&self, other: Cylindric) -> f32 {
+        angle_difference(self.w, other.w)
+    }
+
+    /// Interpolate between two positions; `w` goes the shorter way around the tunnel.
+    pub fn lerp(self, other: Cylindric, t: f32) -> Self {
+        Cylindric::new(
+            self.r + (other.r - self.r) * t,
+            wrap_angle(self.w + self.angle_to(other) * t),
+            self.z + (other.z - self.z) * t,
+        )
+    }
+
+    /// distance along the tunnel wall at radius `r` between the angles of two positions
+    pub fn arc_distance(&self, other: Cylindric, r: f32) -> f32 {
+        self.angle_to(other).abs() * 2.0 * PI * r
+    }
+
+    /// straight-line distance between two positions
+    pub fn distance(&self, other: Cylindric) -> f32 {
+        (Cartesian::from(*self) - Cartesian::from(other)).length()
+    }
 }
 
+impl From<Cartesian> for Cylindric {
+    fn from(c: Cartesian) -> Self {
+        // inverse of `cylindric_pos_to_cartesian`
+        let w = c.x.atan2(-c.y) / (2.0 * PI);
+        Cylindric::new((c.x * c.x + c.y * c.y).sqrt(), wrap_angle(w), c.z)
+    }
+}
+
+/// Component-wise arithmetic. For `Cylindric` this is meant for velocities and for adding them to positions;
+/// `w` is not wrapped, so call `wrapped` on positions afterwards.
+macro_rules! impl_vector_ops {
+    ($t:ident, $a:ident, $b:ident, $c:ident) => {
+        impl Add for $t {
+            type Output = $t;
+            fn add(self, o: $t) -> $t { $t::new(self.$a + o.$a, self.$b + o.$b, self.$c + o.$c) }
+        }
+
+        impl Sub for $t {
+            type Output = $t;
+            fn sub(self, o: $t) -> $t { $t::new(self.$a - o.$a, self.$b - o.$b, self.$c - o.$c) }
+        }
+
+        impl Mul<f32> for $t {
+            type Output = $t;
+            fn mul(self, f: f32) -> $t { $t::new(self.$a * f, self.$b * f, self.$c * f) }
+        }
+
+        impl Neg for $t {
+            type Output = $t;
+            fn neg(self) -> $t { $t::new(-self.$a, -self.$b, -self.$c) }
+        }
+
+        impl AddAssign for $t {
+            fn add_assign(&mut self, o: $t) { *self = *self + o; }
+        }
+
+        impl SubAssign for $t {
+            fn sub_assign(&mut self, o: $t) { *self = *self - o; }
+        }
+    };
+}
+
+impl_vector_ops!(Cartesian, x, y, z);
+impl_vector_ops!(Cylindric, r, w, z);
+
 impl From<Cartesian> for [f32; 3] {
     fn from(vec: Cartesian) -> Self {
         [vec.x, vec.y, vec.z]
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    /// angles in revolutions, including negative ones and ones right at the seam
+    fn angles() -> Vec<f32> {
+        let mut angles: Vec<f32> = (-40..41).map(|i| i as f32 * 0.0625).collect();
+        angles.extend([-1e-8, 1e-8, 0.9999999, -0.9999999, 0.5, -0.5, 123.456, -123.456].iter());
+        angles
+    }
+
+    #[test]
+    fn wrap_angle_is_in_range() {
+        for &w in &angles() {
+            let wrapped = wrap_angle(w);
+            assert!(wrapped >= 0.0 && wrapped < 1.0, "wrap_angle({}) = {}", w, wrapped);
+        }
+    }
+
+    #[test]
+    fn angle_difference_is_in_range() {
+        for &a in &angles() {
+            for &b in &angles() {
+                let d = angle_difference(a, b);
+                assert!(d >= -0.5 && d < 0.5, "angle_difference({}, {}) = {}", a, b, d);
+            }
+        }
+    }
+
+    #[test]
+    fn cylindric_round_trips_through_cartesian() {
+        for &r in [0.0, 0.1, 1.0, 2.5].iter() {
+            for &w in &angles() {
+                for &z in [-3.0, 0.0, 7.5].iter() {
+                    let pos = Cylindric::new(r, w, z);
+                    let back = Cylindric::from(Cartesian::from(pos));
+                    assert!((back.r - r).abs() < EPSILON, "{:?} -> {:?}", pos, back);
+                    assert!((back.z - z).abs() < EPSILON, "{:?} -> {:?}", pos, back);
+                    // the angle is meaningless on the axis
+                    if r > 0.0 {
+                        assert!(angle_difference(w, back.w).abs() < EPSILON, "{:?} -> {:?}", pos, back);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn projections_round_trip() {
+        for kind in ProjectionKind::ALL.iter() {
+            let projection = kind.create();
+            for &r in [0.5, 1.0, 2.0].iter() {
+                for &w in [0.0, 0.125, 0.5, 0.8].iter() {
+                    for &z in [0.0, 1.0, 5.0, 30.0].iter() {
+                        let pos = Cylindric::new(r, w, z);
+                        let screen = projection.project(pos);
+                        let screen = Point2::new(screen.x, screen.y);
+
+                        let back = projection.unproject_at_depth(screen, z)
+                            .unwrap_or_else(|| panic!("{:?}: {:?} not found at its depth", kind, pos));
+                        assert!((back.r - r).abs() < 1e-3, "{:?}: {:?} -> {:?}", kind, pos, back);
+                        assert!(angle_difference(w, back.w).abs() < EPSILON, "{:?}: {:?} -> {:?}", kind, pos, back);
+
+                        let back = projection.unproject_at_radius(screen, r)
+                            .unwrap_or_else(|| panic!("{:?}: {:?} not found at its radius", kind, pos));
+                        assert!((back.z - z).abs() < 1e-3 * (1.0 + z), "{:?}: {:?} -> {:?}", kind, pos, back);
+                        assert!(angle_difference(w, back.w).abs() < EPSILON, "{:?}: {:?} -> {:?}", kind, pos, back);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_is_seen_inside_the_einstein_ring() {
+        let fisheye = Fisheye::default();
+        let inside = Point2::new(0.0, fisheye.einstein_radius * 0.5);
+        assert!(fisheye.unproject_at_depth(inside, 1.0).is_none());
+        assert!(fisheye.unproject_at_radius(inside, 1.0).is_none());
+    }
+
+    #[test]
+    fn lerp_crosses_the_seam() {
+        let a = Cylindric::new(1.0, 0.9, 0.0);
+        let b = Cylindric::new(1.0, 0.1, 0.0);
+
+        let mid = a.lerp(b, 0.5);
+        assert!(angle_difference(0.0, mid.w).abs() < EPSILON, "{:?}", mid);
+
+        let quarter = b.lerp(a, 0.25);
+        assert!((quarter.w - 0.05).abs() < EPSILON, "{:?}", quarter);
+
+        for i in 0..11 {
+            let w = a.lerp(b, i as f32 / 10.0).w;
+            assert!(angle_difference(0.0, w).abs() <= 0.1 + EPSILON, "lerp went the long way: {}", w);
+        }
+    }
+}
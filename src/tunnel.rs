@@ -0,0 +1,243 @@
+use std::f32::consts::PI;
+
+use three_dee::{angle_difference, wrap_angle};
+
+/// distance along the tunnel between two control points of the centreline
+const SEGMENT_LENGTH: f32 = 8.0;
+
+/// distance over which the tunnel morphs from one cross-section into the next
+const TRANSITION_LENGTH: f32 = 4.0;
+
+/// Shape of the tunnel wall, with a radius of 1. Flat sides of polygons are at the bottom.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum CrossSection {
+    Circle,
+    /// regular polygon; `w` is spread evenly over the perimeter
+    Polygon { sides: u32 },
+    /// circle squashed vertically by `aspect`
+    Ellipse { aspect: f32 },
+}
+
+impl CrossSection {
+    /// point on the perimeter at angle `w`, in the same orientation as `cylindric_pos_to_cartesian`
+    pub fn point(&self, w: f32) -> (f32, f32) {
+        match *self {
+            CrossSection::Circle => {
+                let a = w * 2.0 * PI;
+                (a.sin(), -a.cos())
+            }
+            CrossSection::Polygon { sides } => {
+                let n = sides.max(3) as f32;
+                let u = w * n + 0.5;
+                let k = u.floor();
+                let t = u - k;
+                let vertex = |j: f32| {
+                    let a = (j - 0.5) / n * 2.0 * PI;
+                    (a.sin(), -a.cos())
+                };
+                let (x0, y0) = vertex(k);
+                let (x1, y1) = vertex(k + 1.0);
+                (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t)
+            }
+            CrossSection::Ellipse { aspect } => {
+                let a = w * 2.0 * PI;
+                (a.sin(), -a.cos() * aspect)
+            }
+        }
+    }
+
+    /// inverse of `point`: the angle `w` at which the perimeter lies in `direction`, in revolutions
+    pub fn angle(&self, direction: f32) -> f32 {
+        let a = direction * 2.0 * PI;
+        let d = (a.sin(), -a.cos());
+        match *self {
+            CrossSection::Circle => wrap_angle(direction),
+            CrossSection::Polygon { sides } => {
+                let n = sides.max(3) as f32;
+                // the edge that the direction points at, and where along the edge it hits
+                let k = (wrap_angle(direction) * n + 0.5).floor();
+                let vertex = |j: f32| {
+                    let a = (j - 0.5) / n * 2.0 * PI;
+                    (a.sin(), -a.cos())
+                };
+                let (x0, y0) = vertex(k);
+                let (x1, y1) = vertex(k + 1.0);
+                let cross = |x: f32, y: f32| d.0 * y - d.1 * x;
+                let t = -cross(x0, y0) / cross(x1 - x0, y1 - y0);
+                wrap_angle((k + t - 0.5) / n)
+            }
+            CrossSection::Ellipse { aspect } => {
+                wrap_angle((aspect * d.0).atan2(-d.1) / (2.0 * PI))
+            }
+        }
+    }
+
+    /// direction from the wall towards the tunnel axis at angle `w`, as an angle in revolutions
+    pub fn normal(&self, w: f32) -> f32 {
+        match *self {
+            CrossSection::Circle => w,
+            CrossSection::Polygon { sides } => {
+                let n = sides.max(3) as f32;
+                (w * n + 0.5).floor() / n
+            }
+            CrossSection::Ellipse { aspect } => {
+                // gradient of x² + (y / aspect)²
+                let a = w * 2.0 * PI;
+                let (x, y) = (a.sin(), -a.cos() * aspect);
+                x.atan2(-y / (aspect * aspect)) / (2.0 * PI)
+            }
+        }
+    }
+}
+
+/// The course of the tunnel: a centreline that bends sideways and a radius that varies along the way.
+/// Both are Catmull-Rom splines through control points that are derived from the seed, so the same seed
+/// always gives the same tunnel and no points need to be stored.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TunnelPath {
+    pub seed: u64,
+    /// largest sideways offset of the centreline
+    pub bend: f32,
+    /// largest relative change of the radius; 0.3 means the radius varies between 70% and 130%
+    pub narrowing: f32,
+    /// Cross-sections of the first segments of the tunnel, in order. Each segment is `section_length` long.
+    pub layout: Vec<CrossSection>,
+    /// Cross-sections to choose from after the `layout` has run out. Without a layout the tunnel starts with
+    /// the first one; every following segment gets a random one.
+    pub sections: Vec<CrossSection>,
+    pub section_length: f32,
+}
+
+impl Default for TunnelPath {
+    fn default() -> Self {
+        TunnelPath {
+            seed: 0,
+            bend: 0.0,
+            narrowing: 0.0,
+            layout: Vec::new(),
+            sections: vec![CrossSection::Circle],
+            section_length: 32.0,
+        }
+    }
+}
+
+impl TunnelPath {
+    /// sideways offset `(x, y)` of the centreline at distance `s` along the tunnel
+    pub fn offset(&self, s: f32) -> (f32, f32) {
+        if self.bend == 0.0 {
+            return (0.0, 0.0)
+        }
+        let x = self.spline(s, 0) * self.bend;
+        let y = self.spline(s, 1) * self.bend;
+        (x, y)
+    }
+
+    /// factor by which the tunnel radius is scaled at distance `s` along the tunnel
+    pub fn radius_scale(&self, s: f32) -> f32 {
+        (1.0 + self.spline(s, 2) * self.narrowing).max(0.2)
+    }
+
+    /// point on the tunnel wall at angle `w` and distance `s` along the tunnel, for a radius of 1
+    pub fn wall_point(&self, s: f32, w: f32) -> (f32, f32) {
+        let (a, b, t) = self.sections_at(s);
+        let (x0, y0) = a.point(w);
+        if t == 0.0 {
+            return (x0, y0)
+        }
+        let (x1, y1) = b.point(w);
+        (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t)
+    }
+
+    /// Inverse of `wall_point`: the angle `w` at which the wall at distance `s` lies in `direction` from the
+    /// centreline. Both are in revolutions.
+    pub fn wall_angle(&self, s: f32, direction: f32) -> f32 {
+        let (a, _, t) = self.sections_at(s);
+        let mut w = a.angle(direction);
+        if t == 0.0 {
+            return w
+        }
+
+        // while morphing there is no closed form, but the shape is close to both sections and a few
+        // corrections converge
+        for _ in 0..8 {
+            let (x, y) = self.wall_point(s, w);
+            let actual = x.atan2(-y) / (2.0 * PI);
+            w = wrap_angle(w + angle_difference(actual, direction));
+        }
+        w
+    }
+
+    /// direction of the tunnel axis, seen from the wall at angle `w` and distance `s` along the tunnel
+    pub fn wall_normal(&self, s: f32, w: f32) -> f32 {
+        let (a, b, t) = self.sections_at(s);
+        if t < 0.5 { a.normal(w) } else { b.normal(w) }
+    }
+
+    /// the cross-section at `s`, the next one and how far the tunnel has morphed into the next one
+    fn sections_at(&self, s: f32) -> (CrossSection, CrossSection, f32) {
+        let length = self.section_length.max(TRANSITION_LENGTH);
+        let i = (s / length).floor() as i64;
+        let into = s - i as f32 * length;
+        let t = ((into - (length - TRANSITION_LENGTH)) / TRANSITION_LENGTH).max(0.0).min(1.0);
+        (self.section(i), self.section(i + 1), t)
+    }
+
+    fn section(&self, i: i64) -> CrossSection {
+        let i = i.max(0);
+        if let Some(&section) = self.layout.get(i as usize) {
+            return section
+        }
+        if self.sections.is_empty() {
+            return self.layout.last().cloned().unwrap_or(CrossSection::Circle)
+        }
+        if i == 0 {
+            return self.sections[0]
+        }
+        let k = (self.hash(i, 3) * self.sections.len() as f32) as usize;
+        self.sections[k.min(self.sections.len() - 1)]
+    }
+
+    /// value of one of the splines at `s`, roughly in `[-1, 1]`
+    fn spline(&self, s: f32, channel: u64) -> f32 {
+        let u = s / SEGMENT_LENGTH;
+        let i = u.floor() as i64;
+        let t = u - i as f32;
+
+        let p0 = self.control_point(i - 1, channel);
+        let p1 = self.control_point(i, channel);
+        let p2 = self.control_point(i + 1, channel);
+        let p3 = self.control_point(i + 2, channel);
+
+        0.5 * (2.0 * p1
+            + (p2 - p0) * t
+            + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
+            + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t * t * t)
+    }
+
+    /// pseudo-random value in `[-1, 1]` for control point `i`
+    fn control_point(&self, i: i64, channel: u64) -> f32 {
+        // the tunnel starts out straight and round
+        if i <= 0 {
+            return 0.0
+        }
+
+        // bias the values towards 0 so that sharp bends are rare
+        let v = self.hash(i, channel) * 2.0 - 1.0;
+        v * v.abs()
+    }
+
+    /// pseudo-random value in `[0, 1)` for control point `i`
+    fn hash(&self, i: i64, channel: u64) -> f32 {
+        // splitmix64
+        let mut x = self.seed
+            .wrapping_add((i as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+            .wrapping_add(channel.wrapping_mul(0xD1B5_4A32_D192_ED03));
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^= x >> 31;
+
+        (x >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
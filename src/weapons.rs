@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::Duration;
+
+use ggez::{timer, Context, GameError, GameResult};
+
+use toml;
+
+use resources::{ImageId, Resources, SoundId, SynthId};
+
+/// A weapon as written in the weapon definitions file. Times are in seconds and speeds in units along the
+/// tunnel per second.
+///
+/// Every shot goes through the same phases: the projectiles leave the launcher at `launch_speed`, ignite
+/// their engine after `launch_time`, accelerate for `burn_time` and explode after another `flight_time`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WeaponDef {
+    /// image name from the asset manifest
+    pub sprite: String,
+    pub size: f32,
+    /// time between two shots
+    pub cooldown: f32,
+    /// number of projectiles per shot
+    pub projectiles: u32,
+    /// sideways speed of the outermost projectiles of a shot, in revolutions per second
+    pub spread: f32,
+    pub launch_speed: f32,
+    pub launch_time: f32,
+    pub acceleration: f32,
+    pub burn_time: f32,
+    pub flight_time: f32,
+    /// health taken from an enemy that is hit
+    pub damage: f32,
+    /// whether the engine leaves a trail of exhaust
+    pub exhaust: bool,
+    /// how quickly projectiles turn towards the target the player has locked onto, per second; 0 for
+    /// unguided projectiles
+    pub homing: f32,
+    /// names of sounds or synths from the asset manifest
+    pub launch_sound: Option<String>,
+    pub engine_sound: Option<String>,
+    /// name of a sound from the asset manifest; synths cannot be played without an emitter
+    pub explosion_sound: Option<String>,
+}
+
+impl Default for WeaponDef {
+    fn default() -> Self {
+        WeaponDef {
+            sprite: "rocket".to_string(),
+            size: 0.5,
+            cooldown: 0.5,
+            projectiles: 1,
+            spread: 0.0,
+            launch_speed: 1.0,
+            launch_time: 0.0,
+            acceleration: 0.0,
+            burn_time: 0.0,
+            flight_time: 1.0,
+            damage: 10.0,
+            exhaust: false,
+            homing: 0.0,
+            launch_sound: None,
+            engine_sound: None,
+            explosion_sound: None,
+        }
+    }
+}
+
+/// Refers to a weapon loaded into `Weapons`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WeaponId(usize);
+
+#[derive(Debug, Copy, Clone)]
+pub enum WeaponSound {
+    Sample(SoundId),
+    Synth(SynthId),
+}
+
+/// A weapon definition with its assets looked up.
+#[derive(Debug, Clone)]
+pub struct Weapon {
+    pub def: WeaponDef,
+    pub sprite: ImageId,
+    pub launch_sound: Option<WeaponSound>,
+    pub engine_sound: Option<WeaponSound>,
+    pub explosion_sound: Option<SoundId>,
+}
+
+impl Weapon {
+    fn new(name: &str, def: WeaponDef, res: &Resources) -> GameResult<Self> {
+        let sound = |s: &Option<String>| -> GameResult<Option<WeaponSound>> {
+            match *s {
+                Some(ref s) => res.sound(s).map(WeaponSound::Sample)
+                    .or_else(|_| res.synth(s).map(WeaponSound::Synth))
+                    .map(Some)
+                    .map_err(|_| GameError::ResourceLoadError(format!("weapon '{}': unknown sound '{}'", name, s))),
+                None => Ok(None),
+            }
+        };
+
+        let explosion_sound = match def.explosion_sound {
+            Some(ref s) => Some(res.sound(s)
+                .map_err(|_| GameError::ResourceLoadError(format!("weapon '{}': unknown sound '{}'", name, s)))?),
+            None => None,
+        };
+
+        let sprite = res.image(&def.sprite)
+            .map_err(|_| GameError::ResourceLoadError(format!("weapon '{}': unknown image '{}'", name, def.sprite)))?;
+        let launch_sound = sound(&def.launch_sound)?;
+        let engine_sound = sound(&def.engine_sound)?;
+
+        Ok(Weapon { def, sprite, launch_sound, engine_sound, explosion_sound })
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        timer::f64_to_duration(self.def.cooldown.max(0.0) as f64)
+    }
+
+    pub fn launch_time(&self) -> Duration {
+        timer::f64_to_duration(self.def.launch_time.max(0.0) as f64)
+    }
+
+    pub fn burn_time(&self) -> Duration {
+        timer::f64_to_duration(self.def.burn_time.max(0.0) as f64)
+    }
+
+    pub fn flight_time(&self) -> Duration {
+        timer::f64_to_duration(self.def.flight_time.max(0.0) as f64)
+    }
+}
+
+/// All weapon definitions, by name.
+#[derive(Default)]
+pub struct Weapons {
+    /// weapons that were removed from the file while the game was running leave an empty slot
+    weapons: Vec<Option<Weapon>>,
+    names: HashMap<String, WeaponId>,
+}
+
+impl Weapons {
+    /// Load the weapon definitions file at `path`. The assets it refers to must already be loaded. Loading
+    /// the file again updates existing weapons in place, so handles to them stay valid, and retires the
+    /// weapons that are no longer in the file. If any weapon fails to load, nothing is changed.
+    pub fn load(&mut self, ctx: &mut Context, res: &Resources, path: &str) -> GameResult<()> {
+        let mut text = String::new();
+        ctx.filesystem.open(path)?.read_to_string(&mut text)?;
+
+        let defs: HashMap<String, WeaponDef> = toml::from_str(&text)
+            .map_err(|e| GameError::ResourceLoadError(format!("{}: {}", path, e)))?;
+
+        let mut loaded = Vec::with_capacity(defs.len());
+        for (name, def) in defs {
+            let weapon = Weapon::new(&name, def, res)?;
+            loaded.push((name, weapon));
+        }
+
+        for slot in &mut self.weapons {
+            *slot = None;
+        }
+        let mut names = HashMap::new();
+        for (name, weapon) in loaded {
+            let existing = self.names.get(&name).cloned();
+            let id = match existing {
+                Some(id) => id,
+                None => {
+                    self.weapons.push(None);
+                    WeaponId(self.weapons.len() - 1)
+                }
+            };
+            self.weapons[id.0] = Some(weapon);
+            names.insert(name, id);
+        }
+        self.names = names;
+        Ok(())
+    }
+
+    pub fn weapon(&self, name: &str) -> GameResult<WeaponId> {
+        self.names.get(name).cloned()
+            .ok_or_else(|| GameError::ResourceLoadError(format!("unknown weapon '{}'", name)))
+    }
+
+    /// the weapon, unless it has been removed from the definitions file
+    pub fn get(&self, id: WeaponId) -> Option<&Weapon> {
+        self.weapons[id.0].as_ref()
+    }
+}